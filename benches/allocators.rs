@@ -0,0 +1,102 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use wgpu_memory::{simple::SimpleGpuMemory, tlsf::TlsfGpuMemory, AddressId, GpuMemory};
+
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(C)]
+struct Entity {
+    param: u32,
+}
+
+fn get_device() -> wgpu::Device {
+    let instance = wgpu::Instance::new(Default::default());
+
+    let adapter = pollster::block_on(instance.request_adapter(&Default::default())).unwrap();
+
+    let (device, _queue) =
+        pollster::block_on(adapter.request_device(&Default::default(), None)).unwrap();
+
+    device
+}
+
+/// A cheap deterministic sequence of allocation sizes
+fn sizes(count: usize) -> impl Iterator<Item = usize> {
+    let mut seed = 0x2545_f491_u32;
+
+    (0..count).map(move |_| {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+
+        (seed % 64 + 1) as usize
+    })
+}
+
+/// Randomly sized allocations that get freed in a different order than they
+/// were allocated in, fragmenting the buffer
+fn churn<M: GpuMemory<Entity, Index = AddressId>>(mem: &mut M, live: usize) {
+    let mut indices = Vec::with_capacity(live);
+
+    for (i, size) in sizes(live * 8).enumerate() {
+        if indices.len() == live {
+            let index = indices.swap_remove((i * 7) % live);
+            mem.free(index);
+        }
+
+        indices.push(mem.allocate(size));
+    }
+
+    for index in indices {
+        mem.free(index);
+    }
+}
+
+/// A fixed set of allocations where one gets replaced by an allocation of
+/// the same size every iteration
+fn steady_state<M: GpuMemory<Entity, Index = AddressId>>(mem: &mut M, live: usize) {
+    let mut indices = sizes(live)
+        .map(|size| (mem.allocate(size), size))
+        .collect::<Vec<_>>();
+
+    for i in 0..(live * 8) {
+        let (index, size) = indices[i % live];
+        mem.free(index);
+        indices[i % live] = (mem.allocate(size), size);
+    }
+
+    for (index, _) in indices {
+        mem.free(index);
+    }
+}
+
+/// Run every allocation pattern on `M`, named `name` in the reports
+fn bench_allocator<M: GpuMemory<Entity, Index = AddressId>>(
+    c: &mut Criterion,
+    device: &wgpu::Device,
+    name: &str,
+) {
+    for (pattern_name, pattern) in [
+        ("churn", churn::<M> as fn(&mut _, _)),
+        ("steady_state", steady_state::<M>),
+    ] {
+        let mut group = c.benchmark_group(pattern_name);
+
+        for live in [64, 1024] {
+            group.bench_with_input(BenchmarkId::new(name, live), &live, |b, &live| {
+                let mut mem = M::new(wgpu::BufferUsages::empty(), device);
+                b.iter(|| pattern(&mut mem, live));
+            });
+        }
+
+        group.finish();
+    }
+}
+
+fn bench_allocators(c: &mut Criterion) {
+    let device = get_device();
+
+    bench_allocator::<SimpleGpuMemory<Entity>>(c, &device, "SimpleGpuMemory");
+    bench_allocator::<TlsfGpuMemory<Entity>>(c, &device, "TlsfGpuMemory");
+}
+
+criterion_group!(benches, bench_allocators);
+criterion_main!(benches);
@@ -1,11 +1,12 @@
 use std::{
+    hash::{Hash, Hasher},
     marker::PhantomData,
     sync::{Arc, Weak},
 };
 
-use parking_lot::RwLock;
+use parking_lot::{MappedRwLockReadGuard, RwLock, RwLockReadGuard};
 
-use crate::GpuMemory;
+use crate::{stats::Stats, GetManyMutError, GpuMemory, Label};
 
 /// A wrapper struct to wrap another `GpuMemory` buffer, any allocations will be
 /// automatically freed when their index goes out of scope. You should not call
@@ -13,6 +14,11 @@ use crate::GpuMemory;
 /// at a slight cost to every operation on the buffer, so consider this a choice
 /// of developer experience over user experience. The performance hit is trivial
 /// if the buffer isn't written to thousands of times every frame.
+///
+/// The memory returned by `.get()`, `.get_ref()` and `.iter()` isn't locked,
+/// so it's only sound to hold on to it while no index of this buffer is
+/// dropped on another thread, as freeing can move, shrink or poison the
+/// memory. Use `.read()` to read memory while other threads drop indices.
 #[derive(Debug)]
 pub struct AutoDropping<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern, M: GpuMemory<T>> {
     inner: Arc<RwLock<M>>,
@@ -43,6 +49,33 @@ impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern, M: GpuMemory<T>> Cl
     }
 }
 
+impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern, M: GpuMemory<T>> PartialEq
+    for AutoDroppingAddressId<T, M>
+where
+    M::Index: PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.inner == other.inner
+    }
+}
+
+impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern, M: GpuMemory<T>> Eq
+    for AutoDroppingAddressId<T, M>
+where
+    M::Index: Eq,
+{
+}
+
+impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern, M: GpuMemory<T>> Hash
+    for AutoDroppingAddressId<T, M>
+where
+    M::Index: Hash,
+{
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.inner.hash(state)
+    }
+}
+
 impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern, M: GpuMemory<T>> Drop
     for AutoDroppingAddressId<T, M>
 {
@@ -58,6 +91,15 @@ impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern, M: GpuMemory<T>> Dr
     }
 }
 
+impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern, M: GpuMemory<T>> AutoDropping<T, M> {
+    /// Like `.get_ref()`, but the memory is read through a guard that keeps
+    /// the buffer locked until it's dropped, so indices dropped on other
+    /// threads can't free memory that's still being read
+    pub fn read(&self, index: &AutoDroppingAddressId<T, M>) -> MappedRwLockReadGuard<'_, [T]> {
+        RwLockReadGuard::map(self.inner.read(), |inner| inner.get_ref(&index.inner))
+    }
+}
+
 impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern, M: GpuMemory<T>> GpuMemory<T>
     for AutoDropping<T, M>
 {
@@ -106,12 +148,127 @@ impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern, M: GpuMemory<T>> Gp
         }
     }
 
+    fn allocate_labeled(&mut self, count: usize, label: impl Into<Label>) -> Self::Index {
+        let mut inner = self.inner.write();
+
+        let id = inner.allocate_labeled(count, label);
+
+        AutoDroppingAddressId {
+            inner: id,
+            parent: Arc::downgrade(&self.inner),
+            refcount: Arc::new(()),
+            _phantom: Default::default(),
+        }
+    }
+
+    fn label_of(&self, index: &Self::Index) -> Option<Label> {
+        let inner = self.inner.read();
+
+        inner.label_of(&index.inner)
+    }
+
     fn get(&mut self, index: &Self::Index) -> &mut [T] {
         let mut inner = self.inner.write();
 
         unsafe { (inner.get(&index.inner) as *mut [T]).as_mut().unwrap() }
     }
 
+    /// Only sound while no index is dropped on another thread, use `.read()`
+    /// otherwise
+    fn get_ref(&self, index: &Self::Index) -> &[T] {
+        let inner = self.inner.read();
+
+        unsafe {
+            (inner.get_ref(&index.inner) as *const [T])
+                .as_ref()
+                .unwrap()
+        }
+    }
+
+    /// The indices handed out by this don't own their allocation, so they
+    /// don't keep it alive, and dropping them doesn't free it. Only sound
+    /// while no index is dropped on another thread, use `.read()` otherwise
+    fn iter(&self) -> impl Iterator<Item = (Self::Index, &[T])> + '_ {
+        let inner = self.inner.read();
+
+        let allocations = inner
+            .iter()
+            .map(|(index, memory)| {
+                let index = AutoDroppingAddressId {
+                    inner: index,
+                    parent: Weak::new(),
+                    refcount: Arc::new(()),
+                    _phantom: Default::default(),
+                };
+
+                (index, memory as *const [T])
+            })
+            .collect::<Vec<_>>();
+
+        allocations
+            .into_iter()
+            .map(|(index, memory)| (index, unsafe { memory.as_ref().unwrap() }))
+    }
+
+    fn get_many_mut<const N: usize>(
+        &mut self,
+        indices: [&Self::Index; N],
+    ) -> Result<[&mut [T]; N], GetManyMutError> {
+        let mut inner = self.inner.write();
+
+        let memory = inner.get_many_mut(indices.map(|index| &index.inner))?;
+
+        Ok(memory.map(|memory| unsafe { (memory as *mut [T]).as_mut().unwrap() }))
+    }
+
+    /// The indices handed out by this don't own their allocation, like with
+    /// `.iter()`
+    fn iter_mut(&mut self) -> impl Iterator<Item = (Self::Index, &mut [T])> + '_ {
+        let mut inner = self.inner.write();
+
+        let allocations = inner
+            .iter_mut()
+            .map(|(index, memory)| {
+                let index = AutoDroppingAddressId {
+                    inner: index,
+                    parent: Weak::new(),
+                    refcount: Arc::new(()),
+                    _phantom: Default::default(),
+                };
+
+                (index, memory as *mut [T])
+            })
+            .collect::<Vec<_>>();
+
+        allocations
+            .into_iter()
+            .map(|(index, memory)| (index, unsafe { memory.as_mut().unwrap() }))
+    }
+
+    fn write(&mut self, index: &Self::Index, offset: usize, data: &[T]) {
+        let mut inner = self.inner.write();
+
+        inner.write(&index.inner, offset, data);
+    }
+
+    fn fill(&mut self, index: &Self::Index, value: T) {
+        let mut inner = self.inner.write();
+
+        inner.fill(&index.inner, value);
+    }
+
+    fn set(&mut self, index: &Self::Index, i: usize, value: T) {
+        let mut inner = self.inner.write();
+
+        inner.set(&index.inner, i, value);
+    }
+
+    fn copy_allocation(&mut self, source: &Self::Index, destination: &Self::Index) {
+        let mut inner = self.inner.write();
+
+        inner.copy_allocation(&source.inner, &destination.inner);
+    }
+
     fn len(&self) -> usize {
         let inner = self.inner.read();
 
@@ -124,6 +281,12 @@ impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern, M: GpuMemory<T>> Gp
         inner.len_of(&index.inner)
     }
 
+    fn contains(&self, index: &Self::Index) -> bool {
+        let inner = self.inner.read();
+
+        inner.contains(&index.inner)
+    }
+
     fn resize(&mut self, index: &mut Self::Index, len: usize) {
         let mut inner = self.inner.write();
 
@@ -154,13 +317,46 @@ impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern, M: GpuMemory<T>> Gp
         inner.optimize(strategy, queue, device)
     }
 
+    fn recreate(&mut self, queue: &wgpu::Queue, device: &wgpu::Device) {
+        let mut inner = self.inner.write();
+
+        inner.recreate(queue, device)
+    }
+
+    fn write_buffer_with(
+        &mut self,
+        index: &Self::Index,
+        queue: &wgpu::Queue,
+        device: &wgpu::Device,
+        write: impl FnOnce(&mut [T]),
+    ) {
+        let mut inner = self.inner.write();
+
+        inner.write_buffer_with(&index.inner, queue, device, write)
+    }
+
     fn buffer(&self) -> &wgpu::Buffer {
         let inner = self.inner.read();
 
         unsafe { (inner.buffer() as *const wgpu::Buffer).as_ref().unwrap() }
     }
 
-    fn buffer_slice(&self) -> wgpu::BufferSlice {
-        self.buffer().slice(..(self.size() as u64))
+    /// The inner `.buffer_slice()`, as not every allocator keeps its memory
+    /// at the start of the buffer
+    fn buffer_slice(&self) -> wgpu::BufferSlice<'_> {
+        let inner = self.inner.read();
+
+        // Only the lifetime changes, the same as in `.buffer()`
+        unsafe {
+            core::mem::transmute::<wgpu::BufferSlice<'_>, wgpu::BufferSlice<'_>>(
+                inner.buffer_slice(),
+            )
+        }
+    }
+
+    fn stats(&self) -> Stats {
+        let inner = self.inner.read();
+
+        inner.stats()
     }
 }
@@ -0,0 +1,144 @@
+//! Replays a trace recorded with `wgpu_memory::trace::Recording` against the
+//! allocators in this crate, without opening a window, and prints how each of
+//! them did.
+//!
+//! Usage: `wgpu_memory-replay <trace file> [--map <file>] [simple|buddy|tlsf|slab]...`
+//!
+//! With `--map`, the layout of `SimpleGpuMemory` at the end of the trace is
+//! written to `file`, as an SVG if it ends in `.svg` and as a PPM otherwise.
+
+use std::{
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use wgpu_memory::{
+    buddy::BuddyGpuMemory,
+    memory_map::Coloring,
+    simple::SimpleGpuMemory,
+    slab::SlabGpuMemory,
+    tlsf::TlsfGpuMemory,
+    trace::{ReplayReport, Trace, TraceError},
+    GpuMemory,
+};
+
+const ALLOCATORS: [&str; 4] = ["simple", "buddy", "tlsf", "slab"];
+const USAGE: &str =
+    "Usage: wgpu_memory-replay <trace file> [--map <file>] [simple|buddy|tlsf|slab]...";
+
+fn replay(
+    allocator: &str,
+    trace: &Trace,
+    map: Option<&Path>,
+    queue: &wgpu::Queue,
+    device: &wgpu::Device,
+) -> Result<ReplayReport, TraceError> {
+    match allocator {
+        "simple" => {
+            let mut mem = SimpleGpuMemory::<u32>::new(wgpu::BufferUsages::empty(), device);
+            let report = trace.replay_into(&mut mem, queue, device)?;
+
+            if let Some(map) = map {
+                let memory_map = mem.memory_map();
+
+                let bytes = match map.extension().is_some_and(|extension| extension == "svg") {
+                    true => memory_map.to_svg(1024, 64, Coloring::Age).into_bytes(),
+                    false => memory_map.to_ppm(512, 256, Coloring::Age),
+                };
+
+                if let Err(err) = std::fs::write(map, bytes) {
+                    eprintln!("Could not write {}: {err}", map.display());
+                }
+            }
+
+            Ok(report)
+        }
+        "buddy" => trace.replay::<BuddyGpuMemory<u32>>(queue, device),
+        "tlsf" => trace.replay::<TlsfGpuMemory<u32>>(queue, device),
+        "slab" => trace.replay::<SlabGpuMemory<u32>>(queue, device),
+        _ => unreachable!(),
+    }
+}
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+
+    let Some(path) = args.next() else {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    };
+
+    let mut map = None;
+    let mut allocators = Vec::new();
+
+    while let Some(arg) = args.next() {
+        if arg != "--map" {
+            allocators.push(arg);
+            continue;
+        }
+
+        let Some(file) = args.next() else {
+            eprintln!("{USAGE}");
+            return ExitCode::FAILURE;
+        };
+
+        map = Some(PathBuf::from(file));
+    }
+
+    if allocators.is_empty() {
+        allocators = ALLOCATORS.iter().map(|name| name.to_string()).collect();
+    }
+
+    if let Some(unknown) = allocators
+        .iter()
+        .find(|name| !ALLOCATORS.contains(&name.as_str()))
+    {
+        eprintln!("Unknown allocator {unknown}, expected one of {ALLOCATORS:?}");
+        return ExitCode::FAILURE;
+    }
+
+    let trace = match std::fs::read(&path)
+        .map_err(|err| err.to_string())
+        .and_then(|bytes| Trace::from_bytes(&bytes).map_err(|err| err.to_string()))
+    {
+        Ok(trace) => trace,
+        Err(err) => {
+            eprintln!("Could not read {path}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let instance = wgpu::Instance::new(Default::default());
+
+    let Some(adapter) = pollster::block_on(instance.request_adapter(&Default::default())) else {
+        eprintln!("No GPU adapter available");
+        return ExitCode::FAILURE;
+    };
+
+    let (device, queue) =
+        match pollster::block_on(adapter.request_device(&Default::default(), None)) {
+            Ok(device) => device,
+            Err(err) => {
+                eprintln!("Could not create a device: {err}");
+                return ExitCode::FAILURE;
+            }
+        };
+
+    println!(
+        "{path}: {} events, items of {} bytes",
+        trace.events.len(),
+        trace.item_size
+    );
+
+    for allocator in &allocators {
+        match replay(allocator, &trace, map.as_deref(), &queue, &device) {
+            Ok(report) => println!("\n[{allocator}]\n{report}"),
+            Err(err) => {
+                eprintln!("Could not replay {path}: {err}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,418 @@
+use std::{collections::BTreeSet, marker::PhantomData, ops::Range};
+
+use humansize::{format_size, DECIMAL};
+use slotmap::SlotMap;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    recreate_buffer, split_all_mut, split_many_mut,
+    stats::{Counters, Stats},
+    upload_or_resize, AddressId, GetManyMutError, GpuMemory,
+};
+
+#[derive(Debug, Clone)]
+struct Block {
+    /// Offset in items, always a multiple of `1 << order`
+    offset: usize,
+    order: u32,
+    /// The amount of items actually allocated, at most `1 << order`
+    len: usize,
+}
+
+impl Block {
+    fn byte_range<T>(&self) -> Range<usize> {
+        let start = self.offset * core::mem::size_of::<T>();
+
+        start..(start + self.len * core::mem::size_of::<T>())
+    }
+}
+
+/// A buddy system allocator: the buffer is split into blocks of a power of two
+/// items, which are split in half to fit smaller allocations and merged with
+/// their other half (their buddy) again when both are freed. Allocating and
+/// freeing are O(log n), and allocations never move, so their offset in the
+/// buffer can be relied on until they are freed. The trade-off is that every
+/// allocation gets rounded up to a power of two items, wasting up to half of
+/// the memory for unlucky sizes.
+///
+/// This uses the same `AddressId` as `SimpleGpuMemory`, so switching between
+/// them is a matter of changing a type alias.
+#[derive(Debug)]
+pub struct BuddyGpuMemory<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> {
+    buffer: wgpu::Buffer,
+    data: Vec<u8>,
+    /// Offsets of free blocks, indexed by order
+    free_blocks: Vec<BTreeSet<usize>>,
+    blocks: SlotMap<AddressId, Block>,
+    allocated_count: usize,
+    counters: Counters,
+
+    mutated: bool,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> BuddyGpuMemory<T> {
+    /// The offset in bytes of the allocated memory at `index` in the buffer,
+    /// this does not change until the memory is freed or resized
+    pub fn offset(&self, index: &AddressId) -> wgpu::BufferAddress {
+        self.blocks[*index].byte_range::<T>().start as wgpu::BufferAddress
+    }
+
+    /// The amount of items the buffer can hold before it has to grow
+    pub fn capacity(&self) -> usize {
+        match self.max_order() {
+            Some(order) => 1 << order,
+            None => 0,
+        }
+    }
+
+    fn max_order(&self) -> Option<u32> {
+        self.free_blocks
+            .len()
+            .checked_sub(1)
+            .map(|order| order as u32)
+    }
+
+    fn order_of(count: usize) -> u32 {
+        count.max(1).next_power_of_two().trailing_zeros()
+    }
+
+    /// Double the capacity of the buffer until it has a block of `order`
+    fn grow(&mut self, order: u32) {
+        let Some(max_order) = self.max_order() else {
+            self.free_blocks
+                .resize_with(order as usize + 1, BTreeSet::new);
+            self.free_blocks[order as usize].insert(0);
+            self.data
+                .resize(self.capacity() * core::mem::size_of::<T>(), 0);
+
+            return;
+        };
+
+        log::trace!(
+            "Growing buddy allocator of size {} to {}",
+            format_size(self.data.len(), DECIMAL),
+            format_size(self.data.len() * 2, DECIMAL)
+        );
+
+        self.free_blocks.push(BTreeSet::new());
+        self.free_block(1 << max_order, max_order);
+        self.data
+            .resize(self.capacity() * core::mem::size_of::<T>(), 0);
+    }
+
+    /// Find a free block of `order`, splitting bigger blocks if needed
+    fn allocate_block(&mut self, order: u32) -> usize {
+        loop {
+            let free_order = (order as usize..self.free_blocks.len())
+                .find(|&free_order| !self.free_blocks[free_order].is_empty());
+
+            let Some(mut free_order) = free_order else {
+                self.grow(order);
+                continue;
+            };
+
+            let offset = self.free_blocks[free_order].pop_first().unwrap();
+
+            while free_order > order as usize {
+                free_order -= 1;
+                self.free_blocks[free_order].insert(offset + (1 << free_order));
+            }
+
+            return offset;
+        }
+    }
+
+    /// Make a block available again, merging it with its buddy if that is
+    /// free too
+    fn free_block(&mut self, mut offset: usize, mut order: u32) {
+        while (order as usize) + 1 < self.free_blocks.len() {
+            let buddy = offset ^ (1 << order);
+
+            if !self.free_blocks[order as usize].remove(&buddy) {
+                break;
+            }
+
+            offset = offset.min(buddy);
+            order += 1;
+        }
+
+        self.free_blocks[order as usize].insert(offset);
+    }
+
+    /// Drop the upper half of the buffer for as long as it's entirely free
+    fn truncate(&mut self) {
+        while let Some(max_order) = self.max_order() {
+            if self.free_blocks[max_order as usize].contains(&0) {
+                self.free_blocks.clear();
+                break;
+            }
+
+            if max_order == 0 {
+                break;
+            }
+
+            let upper_half = 1 << (max_order - 1);
+
+            if !self.free_blocks[max_order as usize - 1].remove(&upper_half) {
+                break;
+            }
+
+            self.free_blocks.pop();
+        }
+
+        self.data
+            .truncate(self.capacity() * core::mem::size_of::<T>());
+        self.data.shrink_to_fit();
+    }
+}
+
+/// - `Truncate`: shrink the buffer for as long as its upper half is entirely
+///   unallocated. Allocations never move, so this can only free memory at the
+///   end of the buffer.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Strategy {
+    #[default]
+    Truncate,
+}
+
+impl core::fmt::Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Strategy::Truncate => "Truncate",
+            }
+        )
+    }
+}
+
+impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> GpuMemory<T> for BuddyGpuMemory<T> {
+    type Index = AddressId;
+    type OptimizationStrategy = Strategy;
+
+    fn new(usages: wgpu::BufferUsages, device: &wgpu::Device) -> Self {
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("wgpu_text Buddy Allocator"),
+            size: core::mem::size_of::<T>() as wgpu::BufferAddress,
+            usage: usages | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            buffer,
+            data: Vec::new(),
+            free_blocks: Vec::new(),
+            blocks: SlotMap::new(),
+            allocated_count: 0,
+            counters: Counters::default(),
+            mutated: false,
+            _phantom: Default::default(),
+        }
+    }
+
+    fn mutated(&self) -> bool {
+        self.mutated
+    }
+
+    fn allocate(&mut self, count: usize) -> Self::Index {
+        self.mutated = true;
+
+        let order = Self::order_of(count);
+        let offset = self.allocate_block(order);
+
+        self.allocated_count += count;
+        self.counters
+            .record_size(self.allocated_count * core::mem::size_of::<T>());
+
+        self.blocks.insert(Block {
+            offset,
+            order,
+            len: count,
+        })
+    }
+
+    fn get(&mut self, index: &Self::Index) -> &mut [T] {
+        self.mutated = true;
+
+        let range = self.blocks[*index].byte_range::<T>();
+
+        bytemuck::cast_slice_mut(&mut self.data[range])
+    }
+
+    fn get_ref(&self, index: &Self::Index) -> &[T] {
+        bytemuck::cast_slice(&self.data[self.blocks[*index].byte_range::<T>()])
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Self::Index, &[T])> + '_ {
+        self.blocks.iter().map(|(index, block)| {
+            (
+                index,
+                bytemuck::cast_slice(&self.data[block.byte_range::<T>()]),
+            )
+        })
+    }
+
+    fn get_many_mut<const N: usize>(
+        &mut self,
+        indices: [&Self::Index; N],
+    ) -> Result<[&mut [T]; N], GetManyMutError> {
+        let ranges = indices.map(|index| self.blocks[*index].byte_range::<T>());
+        let memory = split_many_mut(&mut self.data, indices, &ranges)?;
+
+        self.mutated = true;
+
+        Ok(memory)
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = (Self::Index, &mut [T])> + '_ {
+        self.mutated = true;
+
+        let allocations = self
+            .blocks
+            .iter()
+            .map(|(index, block)| (index, block.byte_range::<T>()));
+
+        split_all_mut(&mut self.data, allocations).into_iter()
+    }
+
+    fn len(&self) -> usize {
+        self.allocated_count
+    }
+
+    fn len_of(&self, index: &Self::Index) -> usize {
+        self.blocks[*index].len
+    }
+
+    fn contains(&self, index: &Self::Index) -> bool {
+        self.blocks.contains_key(*index)
+    }
+
+    fn resize(&mut self, index: &mut Self::Index, len: usize) {
+        let block = self.blocks[*index].clone();
+        let order = Self::order_of(len);
+
+        self.allocated_count = self.allocated_count - block.len + len;
+        self.counters
+            .record_size(self.allocated_count * core::mem::size_of::<T>());
+
+        if order > block.order {
+            self.mutated = true;
+
+            let offset = self.allocate_block(order);
+            let new_block = Block { offset, order, len };
+
+            let source = block.byte_range::<T>();
+            let destination = new_block.byte_range::<T>().start;
+            self.data.copy_within(source, destination);
+
+            self.free_block(block.offset, block.order);
+            self.blocks[*index] = new_block;
+        } else {
+            // Give back the halves that are no longer needed
+            for free_order in order..block.order {
+                self.free_block(block.offset + (1 << free_order), free_order);
+            }
+
+            self.blocks[*index].order = order;
+            self.blocks[*index].len = len;
+        }
+    }
+
+    fn free(&mut self, index: Self::Index) {
+        self.mutated = true;
+
+        if let Some(block) = self.blocks.remove(index) {
+            self.allocated_count -= block.len;
+
+            self.free_block(block.offset, block.order);
+        }
+    }
+
+    fn upload(&mut self, queue: &wgpu::Queue, device: &wgpu::Device) {
+        if !self.mutated {
+            return;
+        }
+
+        upload_or_resize(queue, device, &mut self.buffer, &self.data);
+        self.counters.record_upload(self.data.len());
+
+        self.mutated = false;
+    }
+
+    fn optimize(
+        &mut self,
+        strategy: Self::OptimizationStrategy,
+        _queue: &wgpu::Queue,
+        device: &wgpu::Device,
+    ) {
+        match strategy {
+            Strategy::Truncate => {
+                let size_before = self.data.len();
+
+                self.truncate();
+
+                log::trace!(
+                    "Truncating GPU buffer of size {} to {}",
+                    format_size(size_before, DECIMAL),
+                    format_size(self.data.len(), DECIMAL)
+                );
+
+                self.buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("wgpu_text Resized Buffer"),
+                    usage: self.buffer.usage() | wgpu::BufferUsages::COPY_DST,
+                    contents: &self.data,
+                });
+                self.counters.record_upload(self.data.len());
+
+                self.mutated = false;
+            }
+        }
+    }
+
+    fn recreate(&mut self, queue: &wgpu::Queue, device: &wgpu::Device) {
+        self.buffer = recreate_buffer(
+            queue,
+            device,
+            "wgpu_text Buddy Allocator",
+            self.buffer.usage(),
+            &self.data,
+            core::mem::size_of::<T>() as wgpu::BufferAddress,
+        );
+        self.counters.record_upload(self.data.len());
+
+        self.mutated = false;
+    }
+
+    fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    /// Allocations are spread out over the entire buffer, so this returns a
+    /// slice of all the memory managed by the allocator, use `.offset()` to
+    /// find where an allocation is stored in the buffer
+    fn buffer_slice(&self) -> wgpu::BufferSlice<'_> {
+        self.buffer.slice(..(self.data.len() as u64))
+    }
+
+    /// The memory lost to rounding allocations up to a power of two is not
+    /// counted as free
+    fn stats(&self) -> Stats {
+        let free_ranges = self
+            .free_blocks
+            .iter()
+            .enumerate()
+            .flat_map(|(order, offsets)| {
+                core::iter::repeat_n((1 << order) * core::mem::size_of::<T>(), offsets.len())
+            });
+
+        Stats::new(
+            self.blocks.len(),
+            free_ranges,
+            &self.buffer,
+            self.data.capacity(),
+            &self.counters,
+        )
+    }
+}
@@ -0,0 +1,172 @@
+use std::{borrow::Cow, ops::Range};
+
+use wgpu::util::StagingBelt;
+
+/// The byte ranges of a buffer that have changed since they were last
+/// uploaded, kept sorted with overlapping and adjacent ranges merged
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DirtyRanges {
+    ranges: Vec<Range<usize>>,
+}
+
+impl DirtyRanges {
+    pub(crate) fn insert(&mut self, range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+
+        // All ranges from `start` up to `end` touch `range`
+        let start = self.ranges.partition_point(|other| other.end < range.start);
+        let end = self
+            .ranges
+            .partition_point(|other| other.start <= range.end);
+
+        if start == end {
+            self.ranges.insert(start, range);
+        } else {
+            let merged =
+                self.ranges[start].start.min(range.start)..self.ranges[end - 1].end.max(range.end);
+
+            self.ranges.splice(start..end, [merged]);
+        }
+    }
+
+    /// Whether any part of `range` is dirty
+    pub(crate) fn overlaps(&self, range: &Range<usize>) -> bool {
+        let index = self
+            .ranges
+            .partition_point(|other| other.end <= range.start);
+
+        self.ranges
+            .get(index)
+            .is_some_and(|other| other.start < range.end)
+    }
+
+    /// Stop tracking `range`, splitting the ranges it's in the middle of
+    pub(crate) fn remove(&mut self, range: &Range<usize>) {
+        let start = self
+            .ranges
+            .partition_point(|other| other.end <= range.start);
+        let end = self.ranges.partition_point(|other| other.start < range.end);
+
+        if start >= end {
+            return;
+        }
+
+        let before = self.ranges[start].start..range.start;
+        let after = range.end..self.ranges[end - 1].end;
+
+        self.ranges.splice(
+            start..end,
+            [before, after]
+                .into_iter()
+                .filter(|other| other.start < other.end),
+        );
+    }
+
+    /// Drop everything before `offset`, moving the rest `offset` bytes down
+    pub(crate) fn cut_front(&mut self, offset: usize) {
+        self.ranges.retain_mut(|range| {
+            range.start = range.start.saturating_sub(offset);
+            range.end = range.end.saturating_sub(offset);
+
+            range.start < range.end
+        });
+    }
+
+    /// Drop everything from `end` on, for when the buffer shrinks
+    pub(crate) fn truncate(&mut self, end: usize) {
+        self.ranges.retain_mut(|range| {
+            range.end = range.end.min(end);
+
+            range.start < range.end
+        });
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.ranges.clear();
+    }
+
+    pub(crate) fn ranges(&self) -> &[Range<usize>] {
+        &self.ranges
+    }
+}
+
+/// Widen `range` to `wgpu::COPY_BUFFER_ALIGNMENT`, returning where it starts
+/// and the bytes of `data` in it, padded with zeroes past the end of `data`
+fn aligned<'a>(data: &'a [u8], range: &Range<usize>) -> (usize, Cow<'a, [u8]>) {
+    let alignment = wgpu::COPY_BUFFER_ALIGNMENT as usize;
+
+    let start = range.start - range.start % alignment;
+    let end = range.end.next_multiple_of(alignment);
+
+    if end <= data.len() {
+        (start, Cow::Borrowed(&data[start..end]))
+    } else {
+        let mut padded = data[start..].to_vec();
+        padded.resize(end - start, 0);
+
+        (start, Cow::Owned(padded))
+    }
+}
+
+/// Write `ranges` of `data` to the same place in `buffer`, the ranges are
+/// widened to `wgpu::COPY_BUFFER_ALIGNMENT`. Returns the amount of bytes
+/// written.
+pub(crate) fn write_ranges(
+    queue: &wgpu::Queue,
+    buffer: &wgpu::Buffer,
+    data: &[u8],
+    ranges: &[Range<usize>],
+) -> usize {
+    let mut written = 0;
+
+    for range in ranges {
+        let (start, bytes) = aligned(data, range);
+
+        queue.write_buffer(buffer, start as u64, &bytes);
+        written += bytes.len();
+    }
+
+    written
+}
+
+/// Like `write_ranges()`, but the writes are recorded into `encoder` through
+/// `belt`
+pub(crate) fn write_ranges_with_belt(
+    belt: &mut StagingBelt,
+    device: &wgpu::Device,
+    encoder: &mut wgpu::CommandEncoder,
+    buffer: &wgpu::Buffer,
+    data: &[u8],
+    ranges: &[Range<usize>],
+) -> usize {
+    let mut written = 0;
+
+    for range in ranges {
+        let (start, bytes) = aligned(data, range);
+
+        write_with_belt(belt, device, encoder, buffer, start as u64, &bytes);
+        written += bytes.len();
+    }
+
+    written
+}
+
+/// Record a write of `bytes` to `buffer` at `offset` into `encoder` through
+/// `belt`, `bytes` has to be a multiple of `wgpu::COPY_BUFFER_ALIGNMENT` long
+pub(crate) fn write_with_belt(
+    belt: &mut StagingBelt,
+    device: &wgpu::Device,
+    encoder: &mut wgpu::CommandEncoder,
+    buffer: &wgpu::Buffer,
+    offset: wgpu::BufferAddress,
+    bytes: &[u8],
+) {
+    let Some(size) = wgpu::BufferSize::new(bytes.len() as u64) else {
+        return;
+    };
+
+    belt.write_buffer(encoder, buffer, offset, size, device)
+        .copy_from_slice(bytes);
+}
@@ -0,0 +1,116 @@
+#[cfg(feature = "backtraces")]
+use std::{backtrace::Backtrace, sync::Arc};
+
+use humansize::{format_size, DECIMAL};
+
+use crate::{simple::GroupId, AddressId, Label};
+
+/// What `SimpleGpuMemory` does when it's dropped while there are still
+/// allocations in it, which usually means `.free()` was forgotten somewhere.
+/// Set with `SimpleGpuMemory::set_on_leak()`.
+#[derive(Default)]
+pub enum OnLeak {
+    /// Drop the allocations without saying anything
+    #[default]
+    Ignore,
+    /// Log the `LeakReport` as a warning
+    Warn,
+    /// Panic with the `LeakReport` in debug builds, and log it as a warning
+    /// in release builds. This never panics while the thread is already
+    /// panicking.
+    Panic,
+    /// Hand the `LeakReport` to a callback
+    Report(Box<dyn Fn(&LeakReport) + Send + Sync>),
+}
+
+impl core::fmt::Debug for OnLeak {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OnLeak::Ignore => write!(f, "Ignore"),
+            OnLeak::Warn => write!(f, "Warn"),
+            OnLeak::Panic => write!(f, "Panic"),
+            OnLeak::Report(_) => write!(f, "Report(..)"),
+        }
+    }
+}
+
+impl OnLeak {
+    pub(crate) fn handle(&self, report: &LeakReport) {
+        match self {
+            OnLeak::Ignore => (),
+            OnLeak::Panic if cfg!(debug_assertions) && !std::thread::panicking() => {
+                panic!("{report}")
+            }
+            OnLeak::Warn | OnLeak::Panic => log::warn!("{report}"),
+            OnLeak::Report(callback) => callback(report),
+        }
+    }
+}
+
+/// An allocation that was still alive, see `LeakReport`
+#[derive(Debug, Clone)]
+pub struct Leak {
+    pub index: AddressId,
+    /// The size of the allocation in bytes
+    pub size: usize,
+    pub label: Option<Label>,
+    pub group: Option<GroupId>,
+    /// Where the allocation was made, only captured with the `backtraces`
+    /// feature
+    #[cfg(feature = "backtraces")]
+    pub backtrace: Option<Arc<Backtrace>>,
+}
+
+/// Every allocation that was still alive in a `SimpleGpuMemory`, from
+/// `SimpleGpuMemory::leaks()` or when it's dropped
+#[derive(Debug, Clone, Default)]
+pub struct LeakReport {
+    /// Sorted from biggest to smallest
+    pub leaks: Vec<Leak>,
+}
+
+impl LeakReport {
+    pub fn is_empty(&self) -> bool {
+        self.leaks.is_empty()
+    }
+
+    /// The total size of the leaked allocations in bytes
+    pub fn size(&self) -> usize {
+        self.leaks.iter().map(|leak| leak.size).sum()
+    }
+}
+
+impl core::fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} allocations leaked, {} in total",
+            self.leaks.len(),
+            format_size(self.size(), DECIMAL)
+        )?;
+
+        for leak in &self.leaks {
+            write!(
+                f,
+                "\n  {:?}: {}",
+                leak.index,
+                format_size(leak.size, DECIMAL)
+            )?;
+
+            if let Some(label) = &leak.label {
+                write!(f, " {label:?}")?;
+            }
+
+            if let Some(group) = leak.group {
+                write!(f, " in {group:?}")?;
+            }
+
+            #[cfg(feature = "backtraces")]
+            if let Some(backtrace) = &leak.backtrace {
+                write!(f, ", allocated at:\n{backtrace}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
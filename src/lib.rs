@@ -5,7 +5,53 @@
 //! frame.
 
 pub mod auto_drop;
+pub mod buddy;
+mod dirty;
+pub mod leak;
+pub mod memory_map;
+pub mod readback;
+pub mod ring;
 pub mod simple;
+pub mod slab;
+pub mod snapshot;
+pub mod stats;
+pub mod tlsf;
+pub mod trace;
+#[cfg(feature = "verify")]
+pub mod verify;
+
+use std::{borrow::Cow, ops::Range};
+
+use itertools::Itertools;
+use stats::Stats;
+
+/// An index into a list of address ranges in the buffer
+pub type AddressId = slotmap::DefaultKey;
+
+/// A debug label of an allocation, either a `&'static str` or a `String`
+pub type Label = Cow<'static, str>;
+
+/// The error returned by `GpuMemory::get_many_mut()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GetManyMutError {
+    /// `indices[first]` and `indices[second]` point at the same allocation
+    DuplicateIndex { first: usize, second: usize },
+}
+
+impl core::fmt::Display for GetManyMutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GetManyMutError::DuplicateIndex { first, second } => {
+                write!(
+                    f,
+                    "Indices {first} and {second} point at the same allocation"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for GetManyMutError {}
 
 pub trait GpuMemory<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> {
     /// The index type to be used to access the memory
@@ -23,6 +69,20 @@ pub trait GpuMemory<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> {
     /// Allocate `count * size_of::<T>()` bytes in the buffer
     fn allocate(&mut self, count: usize) -> Self::Index;
 
+    /// Like `.allocate()`, but with a label to tell the allocation apart
+    /// while debugging, which shows up in `.stats()` and log messages. The
+    /// label stays with the allocation when it's resized.
+    ///
+    /// The default implementation ignores the label.
+    fn allocate_labeled(&mut self, count: usize, _label: impl Into<Label>) -> Self::Index {
+        self.allocate(count)
+    }
+
+    /// The label the allocation at `index` was made with, if any
+    fn label_of(&self, _index: &Self::Index) -> Option<Label> {
+        None
+    }
+
     /// Get a mutable slice to the allocated memory at `index`
     ///
     /// # Safety
@@ -31,12 +91,79 @@ pub trait GpuMemory<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> {
     /// may not be used after .free()
     fn get(&mut self, index: &Self::Index) -> &mut [T];
 
+    /// Get a slice to the allocated memory at `index` to read from, unlike
+    /// `.get()` this doesn't mark anything as changed
+    fn get_ref(&self, index: &Self::Index) -> &[T];
+
+    /// Every live allocation with its memory, in no particular order. Like
+    /// `.get_ref()`, this doesn't mark anything as changed.
+    fn iter(&self) -> impl Iterator<Item = (Self::Index, &[T])> + '_;
+
+    /// Like `.get()`, but for several allocations at once, so memory can be
+    /// copied between them without a temporary copy. Fails if an allocation
+    /// is in `indices` more than once.
+    fn get_many_mut<const N: usize>(
+        &mut self,
+        indices: [&Self::Index; N],
+    ) -> Result<[&mut [T]; N], GetManyMutError>;
+
+    /// Every live allocation with mutable memory, in no particular order.
+    /// Like `.get()`, every allocation gets marked as changed.
+    fn iter_mut(&mut self) -> impl Iterator<Item = (Self::Index, &mut [T])> + '_;
+
+    /// Copy `data` into the allocation at `index`, starting at item `offset`.
+    /// Unlike `.get()`, only the items written are marked as changed where
+    /// the buffer supports it, so only those get uploaded.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `data` doesn't fit in the allocation after `offset`
+    #[track_caller]
+    fn write(&mut self, index: &Self::Index, offset: usize, data: &[T]) {
+        self.get(index)[offset..(offset + data.len())].copy_from_slice(data);
+    }
+
+    /// Set every item in the allocation at `index` to `value`
+    #[track_caller]
+    fn fill(&mut self, index: &Self::Index, value: T) {
+        self.get(index).fill(value);
+    }
+
+    /// Set item `i` of the allocation at `index` to `value`, only marking
+    /// that item as changed where the buffer supports it
+    ///
+    /// # Panics
+    ///
+    /// Panics if `i` is out of bounds of the allocation
+    #[track_caller]
+    fn set(&mut self, index: &Self::Index, i: usize, value: T) {
+        self.get(index)[i] = value;
+    }
+
+    /// Copy the memory of the allocation at `source` into the allocation at
+    /// `destination`, which does nothing if they're the same allocation
+    ///
+    /// # Panics
+    ///
+    /// Panics if the allocations have different lengths
+    #[track_caller]
+    fn copy_allocation(&mut self, source: &Self::Index, destination: &Self::Index) {
+        match self.get_many_mut([source, destination]) {
+            Ok([source, destination]) => destination.copy_from_slice(source),
+            Err(GetManyMutError::DuplicateIndex { .. }) => (),
+        }
+    }
+
     /// The amount of items allocated in the buffer
     fn len(&self) -> usize;
 
     /// The amount of items allocated in the buffer at this `index`
     fn len_of(&self, index: &Self::Index) -> usize;
 
+    /// Whether `index` points at a live allocation in the buffer, `false`
+    /// once it has been freed
+    fn contains(&self, index: &Self::Index) -> bool;
+
     /// Resize the amount of allocated memory at `index`
     fn resize(&mut self, index: &mut Self::Index, len: usize);
 
@@ -55,11 +182,42 @@ pub trait GpuMemory<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> {
         device: &wgpu::Device,
     );
 
+    /// Create the buffer again on `device`, with the same usages and the
+    /// contents of the copy of the buffer on the CPU, for example after the
+    /// device was lost. All indices stay valid.
+    fn recreate(&mut self, queue: &wgpu::Queue, device: &wgpu::Device);
+
+    /// Write to the allocated memory at `index` with `write`, which gets the
+    /// memory in wgpu's staging buffer for the allocation, as handed out by
+    /// `queue.write_buffer_with()`. This skips writing to the CPU copy of the
+    /// buffer first and uploading it later. The buffer is grown first if
+    /// needed.
+    ///
+    /// The staging memory starts out zeroed and replaces the entire
+    /// allocation on the GPU, so every item in it has to be written.
+    ///
+    /// The default implementation writes to `.get(index)` and uploads the
+    /// buffer.
+    fn write_buffer_with(
+        &mut self,
+        index: &Self::Index,
+        queue: &wgpu::Queue,
+        device: &wgpu::Device,
+        write: impl FnOnce(&mut [T]),
+    ) {
+        write(self.get(index));
+        self.upload(queue, device);
+    }
+
     /// Returns the wgpu::Buffer for use in creating a bind group
     fn buffer(&self) -> &wgpu::Buffer;
 
     /// Returns a slice of the buffer containing exactly all the elements in it
-    fn buffer_slice(&self) -> wgpu::BufferSlice;
+    fn buffer_slice(&self) -> wgpu::BufferSlice<'_>;
+
+    /// The amount of allocations, free memory, fragmentation and more, to
+    /// keep an eye on the health of the buffer
+    fn stats(&self) -> Stats;
 
     /// Is the buffer empty
     fn is_empty(&self) -> bool {
@@ -72,6 +230,137 @@ pub trait GpuMemory<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> {
     }
 }
 
+/// Create a buffer on `device` of at least `min_size` bytes, filled with
+/// `data`, for `GpuMemory::recreate()`
+pub(crate) fn recreate_buffer(
+    queue: &wgpu::Queue,
+    device: &wgpu::Device,
+    label: &str,
+    usage: wgpu::BufferUsages,
+    data: &[u8],
+    min_size: u64,
+) -> wgpu::Buffer {
+    let size = (data.len() as u64).next_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT);
+
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some(label),
+        size: size.max(min_size),
+        usage,
+        mapped_at_creation: false,
+    });
+
+    if size == data.len() as u64 {
+        queue.write_buffer(&buffer, 0, data);
+    } else {
+        let mut padded = data.to_vec();
+        padded.resize(size as usize, 0);
+
+        queue.write_buffer(&buffer, 0, &padded);
+    }
+
+    buffer
+}
+
+/// Check that none of `indices` are the same and none of their `ranges`
+/// overlap for `GpuMemory::get_many_mut()`, returning the order they are in
+/// in the buffer. Empty ranges don't overlap anything.
+pub(crate) fn disjoint_order<I: PartialEq, const N: usize>(
+    indices: [&I; N],
+    ranges: &[Range<usize>; N],
+) -> Result<[usize; N], GetManyMutError> {
+    for second in 0..N {
+        if let Some(first) = (0..second).find(|first| indices[*first] == indices[second]) {
+            return Err(GetManyMutError::DuplicateIndex { first, second });
+        }
+    }
+
+    let mut order: [usize; N] = core::array::from_fn(|i| i);
+    order.sort_unstable_by_key(|i| (ranges[*i].start, ranges[*i].end));
+
+    // The range that reaches the furthest so far
+    let mut furthest: Option<usize> = None;
+
+    for i in order {
+        if ranges[i].is_empty() {
+            continue;
+        }
+
+        if let Some(previous) = furthest.filter(|previous| ranges[i].start < ranges[*previous].end)
+        {
+            return Err(GetManyMutError::DuplicateIndex {
+                first: previous.min(i),
+                second: previous.max(i),
+            });
+        }
+
+        furthest = Some(i);
+    }
+
+    Ok(order)
+}
+
+/// Split `ranges` in bytes of `indices` out of `data` as slices of `T`, for
+/// `GpuMemory::get_many_mut()`
+pub(crate) fn split_many_mut<
+    'a,
+    I: PartialEq,
+    T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern,
+    const N: usize,
+>(
+    data: &'a mut [u8],
+    indices: [&I; N],
+    ranges: &[Range<usize>; N],
+) -> Result<[&'a mut [T]; N], GetManyMutError> {
+    let order = disjoint_order(indices, ranges)?;
+
+    let mut slices: [Option<&mut [T]>; N] = [const { None }; N];
+    let mut rest = data;
+    let mut offset = 0;
+
+    for i in order {
+        let range = &ranges[i];
+
+        // Empty ranges can be inside of other ranges
+        if range.is_empty() {
+            slices[i] = Some(&mut []);
+            continue;
+        }
+
+        let (_, tail) = core::mem::take(&mut rest).split_at_mut(range.start - offset);
+        let (slice, tail) = tail.split_at_mut(range.len());
+
+        slices[i] = Some(bytemuck::cast_slice_mut(slice));
+        rest = tail;
+        offset = range.end;
+    }
+
+    Ok(slices.map(Option::unwrap))
+}
+
+/// Split the memory of every allocation out of `data` as slices of `T`, for
+/// `GpuMemory::iter_mut()`. The ranges in bytes must not overlap.
+pub(crate) fn split_all_mut<I, T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern>(
+    data: &mut [u8],
+    allocations: impl IntoIterator<Item = (I, Range<usize>)>,
+) -> Vec<(I, &mut [T])> {
+    let mut rest = data;
+    let mut offset = 0;
+
+    allocations
+        .into_iter()
+        .sorted_by_key(|(_, range)| (range.start, range.end))
+        .map(|(index, range)| {
+            let (_, tail) = core::mem::take(&mut rest).split_at_mut(range.start - offset);
+            let (slice, tail) = tail.split_at_mut(range.len());
+
+            rest = tail;
+            offset = range.end;
+
+            (index, bytemuck::cast_slice_mut(slice))
+        })
+        .collect()
+}
+
 pub fn upload_or_resize(
     queue: &wgpu::Queue,
     device: &wgpu::Device,
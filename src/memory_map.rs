@@ -0,0 +1,201 @@
+use std::{fmt::Write, ops::Range};
+
+use slotmap::Key;
+
+use crate::{simple::GroupId, AddressId, Label};
+
+/// The color of holes left by freed memory
+const HOLE_COLOR: [u8; 3] = [200, 40, 40];
+/// The color of allocations that aren't in a group with `Coloring::Group`,
+/// or don't have a label with `Coloring::Label`
+const UNGROUPED_COLOR: [u8; 3] = [150, 150, 150];
+const GROUP_COLORS: [[u8; 3]; 8] = [
+    [66, 135, 245],
+    [76, 175, 80],
+    [255, 193, 7],
+    [156, 39, 176],
+    [0, 188, 212],
+    [255, 112, 67],
+    [121, 85, 72],
+    [233, 30, 99],
+];
+/// Allocations fade from this color when they are new...
+const NEW_COLOR: [u8; 3] = [255, 220, 60];
+/// ...to this color when they are the oldest in the buffer
+const OLD_COLOR: [u8; 3] = [40, 60, 160];
+
+/// How allocations are colored in an exported memory map, holes are always
+/// red
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Coloring {
+    /// Every group gets its own color, allocations outside of a group are
+    /// gray
+    #[default]
+    Group,
+    /// New allocations are yellow, fading to blue as more allocations are
+    /// made after them
+    Age,
+    /// Every label gets its own color, allocations without a label are gray
+    Label,
+}
+
+/// An allocation in a `MemoryMap`
+#[derive(Debug, Clone)]
+pub struct MappedAllocation {
+    pub index: AddressId,
+    /// Where the allocation is in the buffer, in bytes
+    pub range: Range<usize>,
+    pub group: Option<GroupId>,
+    pub label: Option<Label>,
+    /// The amount of allocations made after this one
+    pub age: u64,
+}
+
+/// The layout of a `SimpleGpuMemory` at some point, taken by
+/// `SimpleGpuMemory::memory_map()`, which can be exported as an image to see
+/// where allocations and holes are in the buffer
+#[derive(Debug, Clone, Default)]
+pub struct MemoryMap {
+    /// The size of the layout in bytes, including holes
+    pub size: usize,
+    /// Every allocation, sorted by where they are in the buffer
+    pub allocations: Vec<MappedAllocation>,
+    /// The holes left by freed memory, sorted by where they are in the buffer
+    pub holes: Vec<Range<usize>>,
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn lerp_color(from: [u8; 3], to: [u8; 3], t: f32) -> [u8; 3] {
+    core::array::from_fn(|i| (from[i] as f32 + (to[i] as f32 - from[i] as f32) * t) as u8)
+}
+
+impl MemoryMap {
+    fn max_age(&self) -> u64 {
+        self.allocations
+            .iter()
+            .map(|allocation| allocation.age)
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn color(&self, allocation: &MappedAllocation, coloring: Coloring, max_age: u64) -> [u8; 3] {
+        match coloring {
+            Coloring::Group => match allocation.group {
+                Some(group) => {
+                    let slot = group.data().as_ffi() as u32 as usize;
+
+                    GROUP_COLORS[slot % GROUP_COLORS.len()]
+                }
+                None => UNGROUPED_COLOR,
+            },
+            Coloring::Label => match &allocation.label {
+                Some(label) => {
+                    let hash = label.bytes().fold(0u32, |hash, byte| {
+                        hash.wrapping_mul(31).wrapping_add(byte as u32)
+                    });
+
+                    GROUP_COLORS[hash as usize % GROUP_COLORS.len()]
+                }
+                None => UNGROUPED_COLOR,
+            },
+            Coloring::Age if max_age == 0 => NEW_COLOR,
+            Coloring::Age => {
+                lerp_color(NEW_COLOR, OLD_COLOR, allocation.age as f32 / max_age as f32)
+            }
+        }
+    }
+
+    /// The allocation at `offset` bytes into the buffer, `None` if it's in a
+    /// hole
+    fn allocation_at(&self, offset: usize) -> Option<&MappedAllocation> {
+        let i = self
+            .allocations
+            .partition_point(|allocation| allocation.range.start <= offset);
+
+        i.checked_sub(1)
+            .map(|i| &self.allocations[i])
+            .filter(|allocation| allocation.range.contains(&offset))
+    }
+
+    /// Render the layout to a binary PPM image of `width` by `height` pixels,
+    /// read left to right and top to bottom like text. Each pixel shows the
+    /// memory at the start of the section of the buffer it covers.
+    pub fn to_ppm(&self, width: usize, height: usize, coloring: Coloring) -> Vec<u8> {
+        let pixels = width * height;
+        let max_age = self.max_age();
+
+        let mut bytes = format!("P6\n{width} {height}\n255\n").into_bytes();
+        bytes.reserve(pixels * 3);
+
+        for pixel in 0..pixels {
+            let offset = (pixel as u128 * self.size as u128 / pixels as u128) as usize;
+
+            bytes.extend(match self.allocation_at(offset) {
+                Some(allocation) => self.color(allocation, coloring, max_age),
+                None => HOLE_COLOR,
+            });
+        }
+
+        bytes
+    }
+
+    /// Render the layout to an SVG image of `width` by `height`, as a single
+    /// bar from the start of the buffer on the left to the end on the right.
+    /// Hovering over an allocation or hole shows where it is.
+    pub fn to_svg(&self, width: u32, height: u32, coloring: Coloring) -> String {
+        let max_age = self.max_age();
+        let scale = match self.size {
+            0 => 0.0,
+            size => width as f64 / size as f64,
+        };
+
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\" \
+             viewBox=\"0 0 {width} {height}\" shape-rendering=\"crispEdges\">\n"
+        );
+
+        let mut rect = |range: &Range<usize>, [r, g, b]: [u8; 3], title: String| {
+            writeln!(
+                svg,
+                "  <rect x=\"{:.3}\" y=\"0\" width=\"{:.3}\" height=\"{height}\" \
+                 fill=\"rgb({r},{g},{b})\"><title>{title}</title></rect>",
+                range.start as f64 * scale,
+                range.len() as f64 * scale,
+            )
+            .unwrap();
+        };
+
+        for allocation in &self.allocations {
+            let group = match allocation.group {
+                Some(group) => format!(", group {group:?}"),
+                None => String::new(),
+            };
+            let label = match &allocation.label {
+                Some(label) => format!(", {}", escape_xml(label)),
+                None => String::new(),
+            };
+
+            rect(
+                &allocation.range,
+                self.color(allocation, coloring, max_age),
+                format!(
+                    "{:?}: bytes {:?}, age {}{group}{label}",
+                    allocation.index, allocation.range, allocation.age
+                ),
+            );
+        }
+
+        for hole in &self.holes {
+            rect(hole, HOLE_COLOR, format!("Hole: bytes {hole:?}"));
+        }
+
+        svg.push_str("</svg>\n");
+
+        svg
+    }
+}
@@ -0,0 +1,162 @@
+use std::{
+    future::Future,
+    marker::PhantomData,
+    ops::Range,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+
+use parking_lot::Mutex;
+use slotmap::SecondaryMap;
+
+use crate::AddressId;
+
+#[derive(Debug, Default)]
+struct ReadbackState {
+    result: Option<Result<(), wgpu::BufferAsyncError>>,
+    waker: Option<Waker>,
+}
+
+/// Memory being copied back from the GPU, resolves once the copy is done.
+///
+/// Like any buffer mapping in wgpu, this only makes progress while the device
+/// is polled, for example with `device.poll(wgpu::Maintain::Wait)`.
+#[derive(Debug)]
+pub struct Readback<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> {
+    staging: wgpu::Buffer,
+    allocations: SecondaryMap<AddressId, Range<usize>>,
+    state: Arc<Mutex<ReadbackState>>,
+    _phantom: PhantomData<fn() -> T>,
+}
+
+impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> Readback<T> {
+    /// Copy `copies` of `buffer` into a new staging buffer of `size` bytes
+    /// and map it, each copy is `(source, destination, len)` in bytes.
+    /// `allocations` are the ranges of the allocations in the staging buffer.
+    pub(crate) fn new(
+        buffer: &wgpu::Buffer,
+        copies: &[(u64, u64, u64)],
+        size: u64,
+        allocations: SecondaryMap<AddressId, Range<usize>>,
+        queue: &wgpu::Queue,
+        device: &wgpu::Device,
+    ) -> Self {
+        let staging = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("wgpu_text Readback Buffer"),
+            size: size.max(wgpu::COPY_BUFFER_ALIGNMENT),
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("wgpu_text Readback"),
+        });
+
+        for &(source, destination, len) in copies {
+            if len > 0 {
+                encoder.copy_buffer_to_buffer(buffer, source, &staging, destination, len);
+            }
+        }
+
+        queue.submit(Some(encoder.finish()));
+
+        let state = Arc::new(Mutex::new(ReadbackState::default()));
+        let callback_state = Arc::clone(&state);
+
+        staging
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |result| {
+                let mut state = callback_state.lock();
+
+                state.result = Some(result);
+
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            });
+
+        Self {
+            staging,
+            allocations,
+            state,
+            _phantom: Default::default(),
+        }
+    }
+
+    /// Block until the copy is done instead of awaiting it, polling `device`
+    pub fn wait(
+        mut self,
+        device: &wgpu::Device,
+    ) -> Result<ReadbackData<T>, wgpu::BufferAsyncError> {
+        loop {
+            device.poll(wgpu::Maintain::Wait);
+
+            let result = self.state.lock().result.take();
+
+            if let Some(result) = result {
+                return self.finish(result);
+            }
+        }
+    }
+
+    fn finish(
+        &mut self,
+        result: Result<(), wgpu::BufferAsyncError>,
+    ) -> Result<ReadbackData<T>, wgpu::BufferAsyncError> {
+        result?;
+
+        let data = self.staging.slice(..).get_mapped_range().to_vec();
+        self.staging.unmap();
+
+        Ok(ReadbackData {
+            data,
+            allocations: core::mem::take(&mut self.allocations),
+            _phantom: Default::default(),
+        })
+    }
+}
+
+impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> Future for Readback<T> {
+    type Output = Result<ReadbackData<T>, wgpu::BufferAsyncError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut state = this.state.lock();
+
+        let Some(result) = state.result.take() else {
+            state.waker = Some(cx.waker().clone());
+
+            return Poll::Pending;
+        };
+
+        drop(state);
+
+        Poll::Ready(this.finish(result))
+    }
+}
+
+/// Memory copied back from the GPU by a `Readback`
+#[derive(Debug, Clone)]
+pub struct ReadbackData<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> {
+    data: Vec<u8>,
+    allocations: SecondaryMap<AddressId, Range<usize>>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> ReadbackData<T> {
+    /// The memory of the allocation at `index` as it was on the GPU, `None`
+    /// if it wasn't read back
+    pub fn get(&self, index: &AddressId) -> Option<&[T]> {
+        let range = self.allocations.get(*index)?;
+
+        Some(bytemuck::cast_slice(&self.data[range.clone()]))
+    }
+
+    /// All allocations that were read back
+    pub fn iter(&self) -> impl Iterator<Item = (AddressId, &[T])> + '_ {
+        self.allocations
+            .iter()
+            .map(|(index, range)| (index, bytemuck::cast_slice(&self.data[range.clone()])))
+    }
+}
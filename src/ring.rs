@@ -0,0 +1,454 @@
+use std::{collections::VecDeque, marker::PhantomData, ops::Range};
+
+use humansize::{format_size, DECIMAL};
+use slotmap::SlotMap;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    dirty::write_ranges,
+    recreate_buffer, split_all_mut, split_many_mut,
+    stats::{Counters, Stats},
+    upload_or_resize, AddressId, GetManyMutError, GpuMemory,
+};
+
+/// The amount of frames `RingGpuMemory::new()` assumes to be in flight
+pub const DEFAULT_FRAMES_IN_FLIGHT: usize = 2;
+
+#[derive(Debug, Clone)]
+struct RingAllocation {
+    /// Position in the ring, this keeps counting up every time the ring wraps
+    /// around, the offset in the buffer is `start % capacity`
+    start: usize,
+    len: usize,
+    frame: u64,
+}
+
+/// A ring buffer for data that only lives for a couple of frames, for example
+/// debug lines, particles or UI quads. Allocating is a simple bump of the
+/// write head, and instead of freeing every allocation individually, all
+/// allocations made in a frame are released at once by `.begin_frame()` when
+/// that frame is `frames_in_flight` frames old. When the ring is exhausted,
+/// it grows to fit.
+#[derive(Debug)]
+pub struct RingGpuMemory<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> {
+    buffer: wgpu::Buffer,
+    data: Vec<u8>,
+    allocations: SlotMap<AddressId, RingAllocation>,
+    /// Every frame that hasn't been released yet, and where in the ring its
+    /// allocations start. The last frame is the current frame.
+    frames: VecDeque<(u64, usize)>,
+    frames_in_flight: usize,
+    frame: u64,
+    head: usize,
+    allocated_count: usize,
+    counters: Counters,
+
+    mutated: bool,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> RingGpuMemory<T> {
+    /// Create a new ring buffer which keeps the allocations of a frame alive
+    /// until `frames_in_flight` more frames have begun
+    pub fn with_frames_in_flight(
+        usages: wgpu::BufferUsages,
+        device: &wgpu::Device,
+        frames_in_flight: usize,
+    ) -> Self {
+        assert!(frames_in_flight > 0, "At least one frame must be in flight");
+
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("wgpu_text Ring Buffer"),
+            size: core::mem::size_of::<T>() as wgpu::BufferAddress,
+            usage: usages | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            buffer,
+            data: Vec::new(),
+            allocations: SlotMap::new(),
+            frames: VecDeque::from([(0, 0)]),
+            frames_in_flight,
+            frame: 0,
+            head: 0,
+            allocated_count: 0,
+            counters: Counters::default(),
+            mutated: false,
+            _phantom: Default::default(),
+        }
+    }
+
+    /// Start a new frame, releasing every allocation made `frames_in_flight`
+    /// frames ago
+    pub fn begin_frame(&mut self) {
+        self.frame += 1;
+        self.frames.push_back((self.frame, self.head));
+
+        let frames_in_flight = self.frames_in_flight as u64;
+
+        while let Some(&(frame, _)) = self.frames.front() {
+            if frame + frames_in_flight > self.frame {
+                break;
+            }
+
+            self.frames.pop_front();
+        }
+
+        let current_frame = self.frame;
+        let mut released_count = 0;
+
+        self.allocations.retain(|_, allocation| {
+            let alive = allocation.frame + frames_in_flight > current_frame;
+
+            if !alive {
+                released_count += allocation.len / core::mem::size_of::<T>();
+            }
+
+            alive
+        });
+
+        self.allocated_count -= released_count;
+    }
+
+    /// The index of the current frame, starting at 0 and increased by every
+    /// call to `.begin_frame()`
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// The amount of bytes the ring can hold before it has to grow
+    pub fn capacity(&self) -> usize {
+        self.data.len()
+    }
+
+    /// The offset in bytes of the allocated memory at `index` in the buffer
+    pub fn offset(&self, index: &AddressId) -> wgpu::BufferAddress {
+        self.physical_range(&self.allocations[*index]).start as wgpu::BufferAddress
+    }
+
+    fn tail(&self) -> usize {
+        self.frames
+            .front()
+            .map(|&(_, start)| start)
+            .unwrap_or(self.head)
+    }
+
+    fn physical_range(&self, allocation: &RingAllocation) -> Range<usize> {
+        let start = match self.data.len() {
+            0 => 0,
+            capacity => allocation.start % capacity,
+        };
+
+        start..(start + allocation.len)
+    }
+
+    /// Find `size` contiguous bytes after the head, growing the ring if there
+    /// is not enough space before the tail
+    fn reserve(&mut self, size: usize) -> usize {
+        let capacity = self.data.len();
+        let tail = self.tail();
+        let mut start = self.head;
+
+        // Allocations may not wrap around the end of the ring
+        if capacity > 0 && start % capacity + size > capacity {
+            start = start.next_multiple_of(capacity);
+        }
+
+        if start + size - tail > capacity {
+            self.relocate((capacity * 2).max(self.head - tail + size));
+
+            return self.reserve(size);
+        }
+
+        self.head = start + size;
+
+        start
+    }
+
+    /// Move all live memory to the front of a ring of `capacity` bytes
+    fn relocate(&mut self, capacity: usize) {
+        let capacity = capacity.next_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT as usize);
+        let tail = self.tail();
+
+        debug_assert!(self.head - tail <= capacity);
+
+        log::trace!(
+            "Relocating ring buffer of size {} to {}",
+            format_size(self.data.len(), DECIMAL),
+            format_size(capacity, DECIMAL)
+        );
+
+        let mut data = vec![0; capacity];
+
+        for allocation in self.allocations.values() {
+            let source = self.physical_range(allocation);
+            let start = allocation.start - tail;
+
+            data[start..(start + allocation.len)].copy_from_slice(&self.data[source]);
+        }
+
+        for allocation in self.allocations.values_mut() {
+            allocation.start -= tail;
+        }
+
+        for (_, start) in self.frames.iter_mut() {
+            *start -= tail;
+        }
+
+        self.head -= tail;
+        self.data = data;
+        self.mutated = true;
+    }
+}
+
+/// - `Truncate`: shrink the ring to the smallest possible size to fit all
+///   memory that hasn't been released yet
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Strategy {
+    #[default]
+    Truncate,
+}
+
+impl core::fmt::Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Strategy::Truncate => "Truncate",
+            }
+        )
+    }
+}
+
+impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> GpuMemory<T> for RingGpuMemory<T> {
+    type Index = AddressId;
+    type OptimizationStrategy = Strategy;
+
+    fn new(usages: wgpu::BufferUsages, device: &wgpu::Device) -> Self {
+        Self::with_frames_in_flight(usages, device, DEFAULT_FRAMES_IN_FLIGHT)
+    }
+
+    fn mutated(&self) -> bool {
+        self.mutated
+    }
+
+    fn allocate(&mut self, count: usize) -> Self::Index {
+        self.mutated = true;
+
+        let len = core::mem::size_of::<T>() * count;
+        let start = self.reserve(len);
+
+        self.allocated_count += count;
+        self.counters
+            .record_size(self.allocated_count * core::mem::size_of::<T>());
+
+        self.allocations.insert(RingAllocation {
+            start,
+            len,
+            frame: self.frame,
+        })
+    }
+
+    fn get(&mut self, index: &Self::Index) -> &mut [T] {
+        self.mutated = true;
+
+        let range = self.physical_range(&self.allocations[*index]);
+
+        bytemuck::cast_slice_mut(&mut self.data[range])
+    }
+
+    fn get_ref(&self, index: &Self::Index) -> &[T] {
+        bytemuck::cast_slice(&self.data[self.physical_range(&self.allocations[*index])])
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Self::Index, &[T])> + '_ {
+        self.allocations.iter().map(|(index, allocation)| {
+            (
+                index,
+                bytemuck::cast_slice(&self.data[self.physical_range(allocation)]),
+            )
+        })
+    }
+
+    fn get_many_mut<const N: usize>(
+        &mut self,
+        indices: [&Self::Index; N],
+    ) -> Result<[&mut [T]; N], GetManyMutError> {
+        let ranges = indices.map(|index| self.physical_range(&self.allocations[*index]));
+        let memory = split_many_mut(&mut self.data, indices, &ranges)?;
+
+        self.mutated = true;
+
+        Ok(memory)
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = (Self::Index, &mut [T])> + '_ {
+        self.mutated = true;
+
+        let allocations = self
+            .allocations
+            .iter()
+            .map(|(index, allocation)| (index, self.physical_range(allocation)))
+            .collect::<Vec<_>>();
+
+        split_all_mut(&mut self.data, allocations).into_iter()
+    }
+
+    fn len(&self) -> usize {
+        self.allocated_count
+    }
+
+    fn len_of(&self, index: &Self::Index) -> usize {
+        self.allocations[*index].len / core::mem::size_of::<T>()
+    }
+
+    fn contains(&self, index: &Self::Index) -> bool {
+        self.allocations.contains_key(*index)
+    }
+
+    fn resize(&mut self, index: &mut Self::Index, len: usize) {
+        let size = len * core::mem::size_of::<T>();
+        let old_len = self.allocations[*index].len;
+
+        if size <= old_len {
+            self.allocations[*index].len = size;
+            self.allocated_count -= (old_len - size) / core::mem::size_of::<T>();
+
+            return;
+        }
+
+        let new_index = self.allocate(len);
+
+        let source = self.physical_range(&self.allocations[*index]);
+        let destination = self.physical_range(&self.allocations[new_index]).start;
+        self.data.copy_within(source, destination);
+
+        self.free(*index);
+        *index = new_index;
+    }
+
+    fn free(&mut self, index: Self::Index) {
+        // The space itself only becomes available again when the frame it was
+        // allocated in gets released by `.begin_frame()`
+        if let Some(allocation) = self.allocations.remove(index) {
+            self.allocated_count -= allocation.len / core::mem::size_of::<T>();
+        }
+    }
+
+    fn upload(&mut self, queue: &wgpu::Queue, device: &wgpu::Device) {
+        if !self.mutated {
+            return;
+        }
+
+        let capacity = self.data.len();
+        let tail = self.tail();
+
+        if self.buffer.size() < capacity as u64 {
+            upload_or_resize(queue, device, &mut self.buffer, &self.data);
+            self.counters.record_upload(capacity);
+        } else if self.head != tail && self.head - tail >= capacity {
+            queue.write_buffer(&self.buffer, 0, &self.data);
+            self.counters.record_upload(capacity);
+        } else if self.head != tail {
+            // The live memory might wrap around the end of the ring, the
+            // capacity is aligned so the ranges can be widened to be written
+            let start = tail % capacity;
+            let end = self.head % capacity;
+
+            let written = if start < end {
+                let range = start..end;
+
+                write_ranges(
+                    queue,
+                    &self.buffer,
+                    &self.data,
+                    core::slice::from_ref(&range),
+                )
+            } else {
+                write_ranges(queue, &self.buffer, &self.data, &[start..capacity, 0..end])
+            };
+
+            self.counters.record_upload(written);
+        }
+
+        self.mutated = false;
+    }
+
+    fn optimize(
+        &mut self,
+        strategy: Self::OptimizationStrategy,
+        _queue: &wgpu::Queue,
+        device: &wgpu::Device,
+    ) {
+        match strategy {
+            Strategy::Truncate => {
+                self.relocate(self.head - self.tail());
+
+                self.buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("wgpu_text Resized Buffer"),
+                    usage: self.buffer.usage() | wgpu::BufferUsages::COPY_DST,
+                    contents: &self.data,
+                });
+                self.counters.record_upload(self.data.len());
+
+                self.mutated = false;
+            }
+        }
+    }
+
+    fn recreate(&mut self, queue: &wgpu::Queue, device: &wgpu::Device) {
+        self.buffer = recreate_buffer(
+            queue,
+            device,
+            "wgpu_text Ring Buffer",
+            self.buffer.usage(),
+            &self.data,
+            core::mem::size_of::<T>() as wgpu::BufferAddress,
+        );
+        self.counters.record_upload(self.data.len());
+
+        self.mutated = false;
+    }
+
+    fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    /// Allocations aren't stored contiguously in a ring, so this returns the
+    /// entire buffer, use `.offset()` to find where an allocation is stored
+    fn buffer_slice(&self) -> wgpu::BufferSlice<'_> {
+        self.buffer.slice(..)
+    }
+
+    /// The free memory is everything between the head and the tail of the
+    /// ring, which is split in two when it wraps around the end of the ring.
+    /// Freed allocations only count once their frame is released.
+    fn stats(&self) -> Stats {
+        let capacity = self.data.len();
+        let (head, tail) = (self.head, self.tail());
+
+        let free_ranges = if capacity == 0 || head - tail >= capacity {
+            Vec::new()
+        } else if head == tail {
+            vec![capacity]
+        } else {
+            let (start, end) = (head % capacity, tail % capacity);
+
+            if start < end {
+                vec![end - start]
+            } else {
+                vec![capacity - start, end]
+            }
+        };
+
+        Stats::new(
+            self.allocations.len(),
+            free_ranges,
+            &self.buffer,
+            self.data.capacity(),
+            &self.counters,
+        )
+    }
+}
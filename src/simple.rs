@@ -1,72 +1,1547 @@
+#[cfg(debug_assertions)]
+use std::panic::Location;
+#[cfg(feature = "backtraces")]
+use std::{backtrace::Backtrace, sync::Arc};
 use std::{cmp::Ordering, marker::PhantomData, ops::Range};
 
 use humansize::{format_size, DECIMAL};
 use itertools::Itertools;
-use slotmap::{DefaultKey, SlotMap};
-use wgpu::util::DeviceExt;
+use slotmap::{DefaultKey, Key, SecondaryMap, SlotMap};
+use wgpu::util::{DeviceExt, StagingBelt};
 
-use crate::{upload_or_resize, GpuMemory};
+#[cfg(feature = "verify")]
+use crate::verify::VerifyError;
+use crate::{
+    dirty::{write_ranges, write_ranges_with_belt, write_with_belt, DirtyRanges},
+    disjoint_order,
+    leak::{Leak, LeakReport, OnLeak},
+    memory_map::{MappedAllocation, MemoryMap},
+    readback::{Readback, ReadbackData},
+    recreate_buffer,
+    snapshot::{Snapshot, SnapshotError, SnapshotState},
+    split_all_mut, split_many_mut,
+    stats::{Counters, Stats},
+    upload_or_resize, GetManyMutError, GpuMemory, Label,
+};
 
-/// An index into a list of address ranges in the buffer
-pub type AddressId = DefaultKey;
+pub use crate::AddressId;
 pub type AddressRange = Range<usize>;
 
-/// Uses a normal buffer, adding `COPY_DST` to the buffer usages.
+const NO_MIRROR_READ: &str =
+    "SimpleGpuMemory::without_mirror() can't be read from on the CPU, use .read_back() instead";
+
+slotmap::new_key_type! {
+    /// A group of allocations that can all be freed at once, see
+    /// `SimpleGpuMemory::allocate_in()`
+    pub struct GroupId;
+}
+
+/// The allocations in a group, see `SimpleGpuMemory::allocate_in()`
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Group {
+    /// Every allocation made in the group, including ones that were freed on
+    /// their own since, which are only dropped once they make up most of it
+    pub(crate) members: Vec<AddressId>,
+    /// The amount of allocations in the group that are still alive
+    pub(crate) len: usize,
+}
+
+/// A section of the buffer that was moved to make room or to reorder it
+#[derive(Debug, Clone, Copy)]
+struct Move {
+    source: usize,
+    destination: usize,
+    len: usize,
+}
+
+/// The newest version every slot of a `SlotMap` handed out, as
+/// `SimpleGpuMemory::restore()` can bring slots back to older versions
+#[derive(Debug, Default)]
+struct Versions(Vec<u32>);
+
+impl Versions {
+    /// The slot of `key` in the `SlotMap` it's from, and its version there
+    fn slot_of(key: impl Key) -> (usize, u32) {
+        let ffi = key.data().as_ffi();
+
+        (ffi as u32 as usize, (ffi >> 32) as u32)
+    }
+
+    /// Remember that `key` was handed out
+    fn record(&mut self, key: impl Key) {
+        let (slot, version) = Self::slot_of(key);
+
+        if slot >= self.0.len() {
+            self.0.resize(slot + 1, 0);
+        }
+
+        self.0[slot] = self.0[slot].max(version);
+    }
+
+    /// If `key` was handed out at some point, as a key that isn't valid
+    /// anymore can't be told apart from a key of another `SlotMap` otherwise
+    fn handed_out(&self, key: impl Key) -> bool {
+        let (slot, version) = Self::slot_of(key);
+
+        self.0.get(slot).is_some_and(|&newest| version <= newest)
+    }
+
+    /// Insert `value` into `map` with a key it never handed out before
+    fn insert<K: Key, V>(&mut self, map: &mut SlotMap<K, V>, value: V) -> K {
+        let mut key = map.insert(value);
+
+        // Removing and inserting again reuses the slot with the next version
+        while let Some(&newest) = self.0.get(Self::slot_of(key).0) {
+            if Self::slot_of(key).1 > newest {
+                break;
+            }
+
+            let value = map.remove(key).unwrap();
+            key = map.insert(value);
+        }
+
+        self.record(key);
+
+        key
+    }
+}
+
+/// Memory of an allocation written without a mirror, see
+/// `SimpleGpuMemory::staged`
+#[derive(Debug, Clone)]
+struct Staged {
+    /// As long as the allocation, zeroed where nothing was written
+    bytes: Vec<u8>,
+    /// The parts of `bytes` that were written, relative to the start of the
+    /// allocation
+    written: DirtyRanges,
+}
+
+impl Staged {
+    fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len],
+            written: DirtyRanges::default(),
+        }
+    }
+
+    /// All of the memory as items, which replace the entire allocation
+    fn items<T: bytemuck::AnyBitPattern + bytemuck::NoUninit>(&mut self) -> &mut [T] {
+        // An empty `Vec<u8>` isn't aligned for `T`
+        if self.bytes.is_empty() {
+            return &mut [];
+        }
+
+        self.written.insert(0..self.bytes.len());
+
+        bytemuck::cast_slice_mut(&mut self.bytes)
+    }
+
+    /// The written parts of the memory with their offsets in the allocation
+    fn written(&self) -> impl Iterator<Item = (usize, &[u8])> + '_ {
+        self.written
+            .ranges()
+            .iter()
+            .map(|range| (range.start, &self.bytes[range.clone()]))
+    }
+}
+
+/// Uses a normal buffer, adding `COPY_DST` and `COPY_SRC` to the buffer
+/// usages.
+///
+/// By default, a copy of the buffer is kept on the CPU. Use
+/// `SimpleGpuMemory::without_mirror()` to only keep the buffer on the GPU.
 #[derive(Debug)]
 pub struct SimpleGpuMemory<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> {
     buffer: wgpu::Buffer,
+    /// The CPU mirror of the buffer, always empty without a mirror
     data: Vec<u8>,
+    /// The end of the last allocation in bytes, including holes
+    end: usize,
+    mirrored: bool,
+    /// Memory written with `.get()` without a mirror, waiting to be written
+    /// to wherever its allocation is at the next upload
+    staged: SecondaryMap<AddressId, Staged>,
+    /// Batches of moves made without a mirror that still have to be made on
+    /// the GPU, in order
+    pending_moves: Vec<Vec<Move>>,
     available_ranges: Vec<AddressRange>,
     used_ranges: SlotMap<AddressId, AddressRange>,
+    address_versions: Versions,
     allocated_count: usize,
+    counters: Counters,
+    groups: SlotMap<GroupId, Group>,
+    group_versions: Versions,
+    group_of: SecondaryMap<AddressId, GroupId>,
+    compact_on_upload: bool,
+    /// The parts of `data` that changed since the last upload, not counting
+    /// memory that was only moved
+    dirty: DirtyRanges,
+    /// Allocations with changes that `.upload_with_budget()` didn't get to
+    /// yet, with a mirror
+    pending: SecondaryMap<AddressId, ()>,
+    priorities: SecondaryMap<AddressId, u32>,
+    /// How many allocations were made before each allocation, to tell their
+    /// age in a `MemoryMap`
+    allocated_at: SecondaryMap<AddressId, u64>,
+    allocations_made: u64,
+    labels: SecondaryMap<AddressId, Label>,
+    on_leak: OnLeak,
+    /// The pattern freed memory is filled with, see `.set_poison()`
+    poison: Option<u32>,
+    /// Freed memory that still has to be poisoned on the GPU, as it's not in
+    /// the mirror
+    poisoned: Vec<AddressRange>,
+    /// Where every freed allocation was freed, to tell use-after-free apart
+    /// from indices that were never allocated
+    #[cfg(debug_assertions)]
+    freed_at: SecondaryMap<AddressId, &'static Location<'static>>,
+    /// Where every allocation was made, for `LeakReport`s
+    #[cfg(feature = "backtraces")]
+    backtraces: SecondaryMap<AddressId, Arc<Backtrace>>,
+    #[cfg(feature = "verify")]
+    verify_every: usize,
+    #[cfg(feature = "verify")]
+    uploads_since_verify: usize,
+
+    mutated: bool,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> SimpleGpuMemory<T> {
+    /// The range of the allocation at `index`, panicking with a message that
+    /// tells why if it's not alive
+    #[track_caller]
+    fn range_of(&self, index: &AddressId) -> &AddressRange {
+        match self.used_ranges.get(*index) {
+            Some(range) => range,
+            None => self.invalid_index(index),
+        }
+    }
+
+    /// Write to the bytes `written` of the allocation at `index` with
+    /// `write`, marking only those bytes as changed
+    #[track_caller]
+    fn write_with(
+        &mut self,
+        index: &AddressId,
+        written: Range<usize>,
+        write: impl FnOnce(&mut [u8]),
+    ) {
+        let range = self.range_of(index).clone();
+
+        assert!(
+            written.end <= range.len(),
+            "Writing to bytes {written:?} of {index:?}, which is only {} bytes long",
+            range.len()
+        );
+
+        if written.is_empty() {
+            return;
+        }
+
+        self.mutated = true;
+
+        if !self.mirrored {
+            let staged = self
+                .staged
+                .entry(*index)
+                .unwrap()
+                .or_insert_with(|| Staged::new(range.len()));
+
+            write(&mut staged.bytes[written.clone()]);
+            staged.written.insert(written);
+
+            return;
+        }
+
+        let written = (range.start + written.start)..(range.start + written.end);
+
+        write(&mut self.data[written.clone()]);
+        self.dirty.insert(written);
+    }
+
+    #[track_caller]
+    fn invalid_index(&self, index: &AddressId) -> ! {
+        #[cfg(debug_assertions)]
+        if let Some(location) = self.freed_at.get(*index) {
+            panic!("{index:?} was used after it was freed at {location}");
+        }
+
+        // Where it was freed is gone once its slot was reused and freed again
+        if self.address_versions.handed_out(*index) {
+            panic!("{index:?} was used after it was freed");
+        }
+
+        panic!("{index:?} was never allocated in this SimpleGpuMemory");
+    }
+
+    fn merge_available_ranges(&mut self, index: usize) {
+        while index + 1 < self.available_ranges.len()
+            && self.available_ranges[index].end >= self.available_ranges[index + 1].start
+        {
+            let right = self.available_ranges.remove(index + 1);
+            let left = &mut self.available_ranges[index];
+
+            left.start = left.start.min(right.start);
+            left.end = left.end.max(right.end);
+        }
+    }
+
+    /// Fill `range` with the poison pattern, if there is one, right away in
+    /// the mirror and at the next upload on the GPU
+    fn poison_range(&mut self, range: &AddressRange) {
+        let Some(pattern) = self.poison else {
+            return;
+        };
+
+        if !self.mirrored || range.end > self.data.len() {
+            self.poisoned.push(range.clone());
+            return;
+        }
+
+        let pattern = pattern.to_le_bytes();
+
+        for (offset, byte) in range.clone().zip(&mut self.data[range.clone()]) {
+            *byte = pattern[offset % pattern.len()];
+        }
+
+        self.dirty.insert(range.clone());
+    }
+
+    /// Stop poisoning `range` on the GPU, as a copy that's made before the
+    /// poison gets written there
+    fn unpoison(&mut self, range: &AddressRange) {
+        self.poisoned = self
+            .poisoned
+            .drain(..)
+            .flat_map(|poisoned| {
+                [
+                    poisoned.start..poisoned.end.min(range.start),
+                    poisoned.start.max(range.end)..poisoned.end,
+                ]
+            })
+            .filter(|poisoned| poisoned.start < poisoned.end)
+            .collect();
+    }
+
+    /// Poison the memory between the end of the layout and `old_end` after
+    /// compacting it, dropping the poison of holes that are gone
+    fn poison_after_compacting(&mut self, old_end: usize) {
+        self.poisoned.clear();
+
+        if self.end < old_end {
+            self.poison_range(&(self.end..old_end));
+        }
+    }
+
+    fn make_range_available(&mut self, range: AddressRange) {
+        self.poison_range(&range);
+
+        let index = self
+            .available_ranges
+            .partition_point(|other_range| other_range.start < range.start);
+
+        self.available_ranges.insert(index, range);
+        self.merge_available_ranges(index);
+
+        if index > 0 {
+            self.merge_available_ranges(index - 1);
+        }
+    }
+
+    /// Like `make_range_available()`, but merges all `ranges` into the
+    /// available ranges in a single pass
+    fn make_ranges_available(&mut self, mut ranges: Vec<AddressRange>) {
+        ranges.sort_unstable_by_key(|range| range.start);
+
+        for range in &ranges {
+            self.poison_range(range);
+        }
+
+        let mut available_ranges: Vec<AddressRange> =
+            Vec::with_capacity(self.available_ranges.len() + ranges.len());
+
+        for range in self
+            .available_ranges
+            .drain(..)
+            .merge_by(ranges, |left, right| left.start <= right.start)
+        {
+            match available_ranges.last_mut() {
+                Some(last) if last.end >= range.start => last.end = last.end.max(range.end),
+                _ => available_ranges.push(range),
+            }
+        }
+
+        self.available_ranges = available_ranges;
+    }
+
+    /// Remove all the holes between memory segments, returning the memory
+    /// that had to be moved
+    fn fix_sequence(&mut self) -> Vec<Move> {
+        if self.available_ranges.is_empty() {
+            return Vec::new();
+        }
+
+        self.available_ranges.clear();
+
+        let old_end = self.end;
+        let mut moves = Vec::new();
+        let mut end = 0;
+
+        for range in self
+            .used_ranges
+            .values_mut()
+            .sorted_by_key(|range| range.start)
+        {
+            if range.start != end {
+                if self.mirrored {
+                    self.data.copy_within(range.clone(), end);
+                }
+
+                moves.push(Move {
+                    source: range.start,
+                    destination: end,
+                    len: range.len(),
+                });
+
+                *range = end..(end + range.len());
+            }
+
+            end = range.end;
+        }
+
+        self.end = end;
+        self.data.truncate(end);
+        self.poison_after_compacting(old_end);
+
+        moves
+    }
+
+    /// Upload the memory that changed since the last upload to where it is
+    /// right now, so the buffer can be rearranged on the GPU. Returns `false`
+    /// if that's not possible because the buffer is too small, or because
+    /// allocations can't be copied on the GPU as the size of `T` is not a
+    /// multiple of `wgpu::COPY_BUFFER_ALIGNMENT`.
+    ///
+    /// Without a mirror this always succeeds, as the buffer is grown on the
+    /// GPU instead.
+    fn flush_dirty(&mut self, queue: &wgpu::Queue, device: &wgpu::Device) -> bool {
+        if !self.mirrored {
+            let size = self.buffer.size().max(self.end as u64);
+
+            self.sync_gpu_layout(queue, device, size);
+            self.flush_staged(queue);
+
+            return true;
+        }
+
+        if self.buffer.size() < self.data.len() as u64
+            || !core::mem::size_of::<T>().is_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT as usize)
+        {
+            return false;
+        }
+
+        self.pending_to_dirty();
+        self.dirty.truncate(self.data.len());
+
+        let written = write_ranges(queue, &self.buffer, &self.data, self.dirty.ranges());
+        self.counters.record_upload(written);
+        self.dirty.clear();
+        self.flush_poison(queue);
+
+        true
+    }
+
+    /// Mark every allocation with changes that haven't been uploaded as
+    /// pending, so they can be found again after moving. What's left is freed
+    /// memory, which still has to be poisoned if it was.
+    fn dirty_to_pending(&mut self) {
+        let mut holes = core::mem::take(&mut self.dirty);
+
+        for (index, range) in &self.used_ranges {
+            if holes.overlaps(range) {
+                self.pending.insert(index, ());
+                holes.remove(range);
+            }
+        }
+
+        if self.poison.is_some() {
+            self.poisoned.extend(holes.ranges().iter().cloned());
+        }
+    }
+
+    fn pending_to_dirty(&mut self) {
+        for (index, ()) in self.pending.drain() {
+            self.dirty.insert(self.used_ranges[index].clone());
+        }
+    }
+
+    /// Take the freed memory that's not in the mirror and still has to be
+    /// poisoned, narrowed to what can be written to the buffer
+    fn take_poisoned(&mut self) -> Vec<AddressRange> {
+        let alignment = wgpu::COPY_BUFFER_ALIGNMENT as usize;
+        let size = self.buffer.size() as usize;
+
+        self.poisoned
+            .drain(..)
+            .map(|range| {
+                // Only whole words can be written, and the buffer can be
+                // smaller than the layout after truncating it
+                range.start.next_multiple_of(alignment)
+                    ..(range.end.min(size) / alignment * alignment)
+            })
+            .filter(|range| range.start < range.end)
+            .collect()
+    }
+
+    /// Write the poison pattern over the freed memory that's not in the mirror
+    fn flush_poison(&mut self, queue: &wgpu::Queue) {
+        let Some(pattern) = self.poison else {
+            self.poisoned.clear();
+            return;
+        };
+
+        for range in self.take_poisoned() {
+            let bytes = pattern
+                .to_le_bytes()
+                .repeat(range.len() / core::mem::size_of::<u32>());
+
+            queue.write_buffer(&self.buffer, range.start as u64, &bytes);
+            self.counters.record_upload(bytes.len());
+        }
+    }
+
+    /// Like `.flush_poison()`, but the poison is copied into the buffer by
+    /// commands recorded into `encoder`, so it lands after the moves recorded
+    /// before it instead of being copied around by them
+    fn record_poison(&mut self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) {
+        let Some(pattern) = self.poison else {
+            self.poisoned.clear();
+            return;
+        };
+
+        let ranges = self.take_poisoned();
+
+        let Some(len) = ranges.iter().map(|range| range.len()).max() else {
+            return;
+        };
+
+        let poison = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("wgpu_text Poison"),
+            contents: &pattern
+                .to_le_bytes()
+                .repeat(len / core::mem::size_of::<u32>()),
+            usage: wgpu::BufferUsages::COPY_SRC,
+        });
+
+        for range in ranges {
+            encoder.copy_buffer_to_buffer(
+                &poison,
+                0,
+                &self.buffer,
+                range.start as u64,
+                range.len() as u64,
+            );
+            self.counters.record_upload(range.len());
+        }
+    }
+
+    /// Write the poison pattern and the memory staged by `.get()` without a
+    /// mirror to where it is right now. Poison goes first, so allocations
+    /// made in freed memory since the last upload are written over it.
+    fn flush_staged(&mut self, queue: &wgpu::Queue) {
+        self.flush_poison(queue);
+
+        for (index, staged) in self.staged.drain() {
+            let start = self.used_ranges[index].start;
+
+            for (offset, bytes) in staged.written() {
+                queue.write_buffer(&self.buffer, (start + offset) as u64, bytes);
+                self.counters.record_upload(bytes.len());
+            }
+        }
+    }
+
+    fn push_moves(&mut self, moves: Vec<Move>) {
+        if !self.mirrored && !moves.is_empty() {
+            self.pending_moves.push(moves);
+        }
+    }
+
+    /// Make the moves that were made without a mirror on the GPU, and resize
+    /// the buffer to `size` bytes, copying over as much of it as fits
+    fn sync_gpu_layout(&mut self, queue: &wgpu::Queue, device: &wgpu::Device, size: u64) {
+        if self.pending_moves.is_empty() && self.buffer.size() == size {
+            return;
+        }
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("wgpu_text Buffer Allocator"),
+        });
+
+        self.record_gpu_layout(size, device, &mut encoder);
+
+        queue.submit(Some(encoder.finish()));
+    }
+
+    /// Like `.sync_gpu_layout()`, but the commands are recorded into `encoder`
+    fn record_gpu_layout(
+        &mut self,
+        size: u64,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        // Moves can reach past the end of the buffer when memory was
+        // allocated since the last upload, so it has to grow before moving
+        let extent = self
+            .pending_moves
+            .iter()
+            .flatten()
+            .map(|m| (m.source.max(m.destination) + m.len) as u64)
+            .max()
+            .unwrap_or(0);
+
+        if self.buffer.size() < size.max(extent) {
+            self.resize_buffer(size.max(extent), device, encoder);
+        }
+
+        for moves in core::mem::take(&mut self.pending_moves) {
+            self.record_moves(&moves, device, encoder);
+        }
+
+        if self.buffer.size() != size {
+            self.resize_buffer(size, device, encoder);
+        }
+    }
+
+    /// Replace the buffer with one of `size` bytes, recording a copy of as
+    /// much of the old buffer as fits into `encoder`
+    fn resize_buffer(
+        &mut self,
+        size: u64,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        log::trace!(
+            "Resizing GPU buffer of size {} to {}",
+            format_size(self.buffer.size(), DECIMAL),
+            format_size(size, DECIMAL)
+        );
+
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("wgpu_text Resized Buffer"),
+            size,
+            usage: self.buffer.usage(),
+            mapped_at_creation: false,
+        });
+
+        let copy_size = self.buffer.size().min(size);
+
+        if copy_size > 0 {
+            encoder.copy_buffer_to_buffer(&self.buffer, 0, &buffer, 0, copy_size);
+        }
+
+        self.buffer = buffer;
+    }
+
+    /// Record `moves` as copies within the buffer into `encoder`. All moved
+    /// memory is copied to a scratch buffer first, so moves can overlap.
+    fn record_moves(
+        &self,
+        moves: &[Move],
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        let scratch_size = moves.iter().map(|m| m.len).sum::<usize>() as u64;
+
+        if scratch_size == 0 {
+            return;
+        }
+
+        let scratch = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("wgpu_text Scratch Buffer"),
+            size: scratch_size,
+            usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut offset = 0;
+
+        for m in moves {
+            encoder.copy_buffer_to_buffer(
+                &self.buffer,
+                m.source as u64,
+                &scratch,
+                offset,
+                m.len as u64,
+            );
+            offset += m.len as u64;
+        }
+
+        let mut offset = 0;
+
+        for m in moves {
+            encoder.copy_buffer_to_buffer(
+                &scratch,
+                offset,
+                &self.buffer,
+                m.destination as u64,
+                m.len as u64,
+            );
+            offset += m.len as u64;
+        }
+    }
+
+    /// Like `.upload()`, but only the memory that changed since the last
+    /// upload is written, through `belt` into `encoder`, so the upload can be
+    /// batched with the rest of the commands of a frame. Growing and
+    /// compacting the buffer are recorded as copies on the GPU into
+    /// `encoder` too.
+    ///
+    /// As with any use of a `StagingBelt`, call `belt.finish()` before
+    /// submitting `encoder`, and `belt.recall()` after.
+    ///
+    /// Falls back to a normal `.upload()` if the size of `T` is not a
+    /// multiple of `wgpu::COPY_BUFFER_ALIGNMENT`.
+    pub fn upload_with_encoder(
+        &mut self,
+        queue: &wgpu::Queue,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        belt: &mut StagingBelt,
+    ) {
+        if !self.mutated {
+            return;
+        }
+
+        if !core::mem::size_of::<T>().is_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT as usize) {
+            return self.upload(queue, device);
+        }
+
+        if self.mirrored {
+            if self.buffer.size() < self.end as u64 {
+                self.resize_buffer(self.end as u64, device, encoder);
+            }
+
+            self.pending_to_dirty();
+            self.dirty.truncate(self.data.len());
+
+            let written = write_ranges_with_belt(
+                belt,
+                device,
+                encoder,
+                &self.buffer,
+                &self.data,
+                self.dirty.ranges(),
+            );
+            self.counters.record_upload(written);
+
+            if self.compact_on_upload {
+                let moves = self.fix_sequence();
+                self.record_moves(&moves, device, encoder);
+            }
+
+            self.record_poison(device, encoder);
+        } else {
+            if self.compact_on_upload {
+                let moves = self.fix_sequence();
+                self.push_moves(moves);
+            }
+
+            let size = self.buffer.size().max(self.end as u64);
+            self.record_gpu_layout(size, device, encoder);
+            self.record_poison(device, encoder);
+
+            for (index, staged) in self.staged.drain() {
+                let start = self.used_ranges[index].start;
+
+                for (offset, bytes) in staged.written() {
+                    let offset = (start + offset) as wgpu::BufferAddress;
+
+                    write_with_belt(belt, device, encoder, &self.buffer, offset, bytes);
+                    self.counters.record_upload(bytes.len());
+                }
+            }
+        }
+
+        self.dirty.clear();
+        self.mutated = false;
+    }
+
+    /// Copy the entire buffer back from the GPU, for example after a compute
+    /// shader wrote to it. This reads what is on the GPU right now, so
+    /// changes that haven't been uploaded yet are not included, and the
+    /// buffer shouldn't be changed until the readback resolves.
+    ///
+    /// The buffer always has `COPY_SRC` usage, so this works for any buffer.
+    pub fn read_back(&self, queue: &wgpu::Queue, device: &wgpu::Device) -> Readback<T> {
+        let alignment = wgpu::COPY_BUFFER_ALIGNMENT as usize;
+
+        let size = (self.end.next_multiple_of(alignment) as u64).min(self.buffer.size());
+        let allocations = self
+            .used_ranges
+            .iter()
+            .filter(|(_, range)| range.end as u64 <= size)
+            .map(|(index, range)| (index, range.clone()))
+            .collect();
+
+        Readback::new(
+            &self.buffer,
+            &[(0, 0, size)],
+            size,
+            allocations,
+            queue,
+            device,
+        )
+    }
+
+    /// Like `.read_back()`, but only copies the allocations at `indices`
+    pub fn read_back_allocations(
+        &self,
+        indices: &[AddressId],
+        queue: &wgpu::Queue,
+        device: &wgpu::Device,
+    ) -> Readback<T> {
+        let alignment = wgpu::COPY_BUFFER_ALIGNMENT as usize;
+
+        let mut copies = Vec::with_capacity(indices.len());
+        let mut allocations = SecondaryMap::new();
+        let mut size = 0;
+
+        for index in indices {
+            let range = self.range_of(index);
+
+            // Copies have to be aligned, so copy a bit more if needed
+            let start = range.start - range.start % alignment;
+            let end = range.end.next_multiple_of(alignment);
+
+            if end as u64 > self.buffer.size() {
+                continue;
+            }
+
+            copies.push((start as u64, size as u64, (end - start) as u64));
+
+            let offset = size + range.start - start;
+            allocations.insert(*index, offset..(offset + range.len()));
+
+            size += end - start;
+        }
+
+        Readback::new(
+            &self.buffer,
+            &copies,
+            size as u64,
+            allocations,
+            queue,
+            device,
+        )
+    }
+
+    /// Write memory that was read back from the GPU into the CPU mirror, for
+    /// every allocation in `readback` that still exists and has the same
+    /// length. This doesn't mark anything as changed, as the GPU already has
+    /// this memory. Does nothing without a mirror.
+    pub fn write_back(&mut self, readback: &ReadbackData<T>) {
+        if !self.mirrored {
+            return;
+        }
+
+        for (index, items) in readback.iter() {
+            let Some(range) = self.used_ranges.get(index) else {
+                continue;
+            };
+
+            if range.len() == core::mem::size_of_val(items) {
+                self.data[range.clone()].copy_from_slice(bytemuck::cast_slice(items));
+            }
+        }
+    }
+
+    /// Read the buffer back from the GPU and compare it to the CPU mirror,
+    /// returning the first allocation that differs. Allocations with changes
+    /// that haven't been uploaded yet are skipped. This blocks until the
+    /// buffer has been read back, so it's only meant for debugging.
+    ///
+    /// Without a mirror there's nothing to compare to, so this always
+    /// succeeds.
+    #[cfg(feature = "verify")]
+    pub fn verify(&self, queue: &wgpu::Queue, device: &wgpu::Device) -> Result<(), VerifyError> {
+        if !self.mirrored {
+            return Ok(());
+        }
+
+        let readback = self.read_back(queue, device).wait(device)?;
+
+        for (index, range) in self
+            .used_ranges
+            .iter()
+            .sorted_by_key(|(_, range)| range.start)
+        {
+            if self.is_pending(&index) {
+                continue;
+            }
+
+            let Some(items) = readback.get(&index) else {
+                continue;
+            };
+
+            let gpu: &[u8] = bytemuck::cast_slice(items);
+            let cpu = &self.data[range.clone()];
+
+            let Some(first) = gpu.iter().zip(cpu).position(|(a, b)| a != b) else {
+                continue;
+            };
+            let last = gpu.iter().zip(cpu).rposition(|(a, b)| a != b).unwrap();
+
+            return Err(VerifyError::Mismatch {
+                index,
+                range: (range.start + first)..(range.start + last + 1),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Run `.verify()` after every `uploads` calls to `.upload()`, panicking
+    /// if it fails. 0 turns this off, which is the default.
+    #[cfg(feature = "verify")]
+    pub fn set_verify_every(&mut self, uploads: usize) {
+        self.verify_every = uploads;
+        self.uploads_since_verify = 0;
+    }
+
+    /// Save the state of the allocator and the memory in it, to bring it back
+    /// later with `.restore()`. Without a mirror, only the allocations are
+    /// saved and not the memory in them.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut groups = self.groups.clone();
+
+        for group in groups.values_mut() {
+            group
+                .members
+                .retain(|member| self.group_of.contains_key(*member));
+        }
+
+        Snapshot {
+            state: SnapshotState {
+                item_size: core::mem::size_of::<T>(),
+                mirrored: self.mirrored,
+                data: self.data.clone(),
+                end: self.end,
+                available_ranges: self.available_ranges.clone(),
+                used_ranges: self.used_ranges.clone(),
+                allocated_count: self.allocated_count,
+                groups,
+                group_of: self.group_of.clone(),
+                priorities: self.priorities.clone(),
+                labels: self.labels.clone(),
+                compact_on_upload: self.compact_on_upload,
+            },
+        }
+    }
+
+    /// Bring back the state from `snapshot`, every `AddressId` and `GroupId`
+    /// from when the snapshot was taken is valid again with its label, and
+    /// any created since then is not, not even after allocating again. The
+    /// entire buffer gets uploaded again at the next upload.
+    ///
+    /// Without a mirror, there is no memory in the snapshot, so every
+    /// allocation has to be written again.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        let state = snapshot.state.clone();
+
+        if state.item_size != core::mem::size_of::<T>() {
+            return Err(SnapshotError::ItemSizeMismatch {
+                expected: core::mem::size_of::<T>(),
+                found: state.item_size,
+            });
+        }
+
+        if state.mirrored != self.mirrored {
+            return Err(SnapshotError::MirrorMismatch);
+        }
+
+        state.validate()?;
+
+        self.data = state.data;
+        self.end = state.end;
+        self.available_ranges = state.available_ranges;
+
+        // Indices and groups made since the snapshot stay invalid
+        state
+            .used_ranges
+            .keys()
+            .for_each(|index| self.address_versions.record(index));
+        state
+            .groups
+            .keys()
+            .for_each(|group| self.group_versions.record(group));
+
+        self.used_ranges = state.used_ranges;
+        self.allocated_count = state.allocated_count;
+        self.counters
+            .record_size(self.allocated_count * core::mem::size_of::<T>());
+        self.groups = state.groups;
+        self.group_of = state.group_of;
+        self.priorities = state.priorities;
+        self.labels = state.labels;
+        self.compact_on_upload = state.compact_on_upload;
+
+        self.staged.clear();
+        self.pending_moves.clear();
+        self.poisoned.clear();
+        self.pending.clear();
+        self.allocated_at.clear();
+        #[cfg(feature = "backtraces")]
+        self.backtraces.clear();
+        #[cfg(debug_assertions)]
+        self.freed_at.clear();
+        self.dirty.clear();
+        self.dirty.insert(0..self.end);
+        self.mutated = true;
+
+        Ok(())
+    }
+
+    /// Set the priority of the allocation at `index` for
+    /// `.upload_with_budget()`, allocations with a higher priority get
+    /// uploaded first. The default priority is 0.
+    pub fn set_priority(&mut self, index: &AddressId, priority: u32) {
+        self.priorities.insert(*index, priority);
+    }
+
+    /// Like `.upload()`, but only uploads up to `max_bytes` of the allocations
+    /// that changed since they were last uploaded, highest priority first. The
+    /// rest stay pending until a following call gets to them, see
+    /// `.is_pending()`. At least one allocation gets uploaded every call, so
+    /// allocations bigger than `max_bytes` are still uploaded eventually.
+    ///
+    /// Growing and compacting the buffer happen with copies on the GPU, which
+    /// don't count towards the budget.
+    ///
+    /// Falls back to a normal `.upload()` if the size of `T` is not a
+    /// multiple of `wgpu::COPY_BUFFER_ALIGNMENT`.
+    pub fn upload_with_budget(
+        &mut self,
+        queue: &wgpu::Queue,
+        device: &wgpu::Device,
+        max_bytes: usize,
+    ) {
+        if !self.mutated {
+            return;
+        }
+
+        if !core::mem::size_of::<T>().is_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT as usize) {
+            return self.upload(queue, device);
+        }
+
+        if self.mirrored {
+            self.dirty_to_pending();
+        } else {
+            // Everything that changed is in `staged` instead
+            self.dirty.clear();
+        }
+
+        if self.compact_on_upload {
+            let moves = self.fix_sequence();
+
+            if !moves.is_empty() {
+                self.pending_moves.push(moves);
+            }
+        }
+
+        let size = self.buffer.size().max(self.end as u64);
+        self.sync_gpu_layout(queue, device, size);
+        self.flush_poison(queue);
+
+        let pending = if self.mirrored {
+            self.pending.keys().collect::<Vec<_>>()
+        } else {
+            self.staged.keys().collect::<Vec<_>>()
+        };
+
+        let mut budget = max_bytes;
+
+        for (i, index) in pending
+            .into_iter()
+            .sorted_by_key(|index| {
+                let priority = self.priorities.get(*index).copied().unwrap_or(0);
+
+                (core::cmp::Reverse(priority), self.used_ranges[*index].start)
+            })
+            .enumerate()
+        {
+            let range = self.used_ranges[index].clone();
+
+            if range.len() > budget && i > 0 {
+                break;
+            }
+
+            budget = budget.saturating_sub(range.len());
+
+            if self.mirrored {
+                self.pending.remove(index);
+
+                let bytes = &self.data[range.clone()];
+
+                if !bytes.is_empty() {
+                    queue.write_buffer(&self.buffer, range.start as u64, bytes);
+                    self.counters.record_upload(bytes.len());
+                }
+            } else {
+                for (offset, bytes) in self.staged.remove(index).unwrap().written() {
+                    queue.write_buffer(&self.buffer, (range.start + offset) as u64, bytes);
+                    self.counters.record_upload(bytes.len());
+                }
+            }
+        }
+
+        self.mutated = !self.pending.is_empty() || !self.staged.is_empty();
+    }
+
+    /// Whether the allocation at `index` has changes that haven't been
+    /// uploaded yet, for example because `.upload_with_budget()` ran out of
+    /// budget. Rendering can skip it or use a placeholder in the meantime.
+    #[track_caller]
+    pub fn is_pending(&self, index: &AddressId) -> bool {
+        self.pending.contains_key(*index)
+            || self.staged.contains_key(*index)
+            || self.dirty.overlaps(self.range_of(index))
+    }
+
+    /// All allocations with changes that haven't been uploaded yet, see
+    /// `.is_pending()`
+    pub fn pending(&self) -> impl Iterator<Item = AddressId> + '_ {
+        self.used_ranges
+            .keys()
+            .filter(|index| self.is_pending(index))
+    }
+
+    /// Like the compaction done by `.upload()`, but instead of moving memory
+    /// around on the CPU and uploading the entire buffer again, the moves are
+    /// recorded as copies on the GPU into `encoder`, so only memory that was
+    /// changed since the last upload gets uploaded. The CPU buffer is updated
+    /// to match right away.
+    ///
+    /// Falls back to a normal `.upload()` when the buffer has to grow, or if
+    /// the size of `T` is not a multiple of `wgpu::COPY_BUFFER_ALIGNMENT`.
+    pub fn compact_with_encoder(
+        &mut self,
+        queue: &wgpu::Queue,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        if !self.flush_dirty(queue, device) {
+            return self.upload(queue, device);
+        }
+
+        let moves = self.fix_sequence();
+        self.record_moves(&moves, device, encoder);
+        self.record_poison(device, encoder);
+
+        self.mutated = false;
+    }
+
+    /// Like `.optimize()`, but memory that has to be moved is copied on the
+    /// GPU by commands recorded into `encoder`, instead of uploading the
+    /// entire buffer again. The CPU buffer is updated to match right away.
+    ///
+    /// Falls back to `.optimize()` when the buffer has to grow, or if the
+    /// size of `T` is not a multiple of `wgpu::COPY_BUFFER_ALIGNMENT`.
+    pub fn optimize_with_encoder(
+        &mut self,
+        strategy: Strategy,
+        queue: &wgpu::Queue,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+    ) {
+        if !self.flush_dirty(queue, device) {
+            return self.optimize(strategy, queue, device);
+        }
+
+        let moves = match strategy {
+            Strategy::Truncate => self.fix_sequence(),
+            Strategy::SortSizeDescending => self.sort(true),
+            Strategy::SortSizeAscending => self.sort(false),
+        };
+
+        self.mutated = false;
+
+        match strategy {
+            Strategy::Truncate => {
+                let size = self.end;
+
+                log::trace!(
+                    "Truncating GPU buffer of size {} to {}",
+                    format_size(self.buffer.size(), DECIMAL),
+                    format_size(size, DECIMAL)
+                );
+
+                let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("wgpu_text Resized Buffer"),
+                    size: size as u64,
+                    usage: self.buffer.usage(),
+                    mapped_at_creation: false,
+                });
+
+                // Everything before the first moved allocation stays where it is
+                let unmoved = moves.first().map(|m| m.destination).unwrap_or(size);
+
+                if unmoved > 0 {
+                    encoder.copy_buffer_to_buffer(&self.buffer, 0, &buffer, 0, unmoved as u64);
+                }
+
+                for m in &moves {
+                    encoder.copy_buffer_to_buffer(
+                        &self.buffer,
+                        m.source as u64,
+                        &buffer,
+                        m.destination as u64,
+                        m.len as u64,
+                    );
+                }
+
+                self.buffer = buffer;
+                self.data.shrink_to_fit();
+            }
+            Strategy::SortSizeDescending | Strategy::SortSizeAscending => {
+                self.record_moves(&moves, device, encoder);
+            }
+        }
+
+        self.record_poison(device, encoder);
+    }
+
+    /// Like `SimpleGpuMemory::new()`, but without keeping a copy of the
+    /// buffer on the CPU, so only the memory on the GPU is paid for. Memory
+    /// is moved and grown with copies on the GPU instead.
+    ///
+    /// The buffer can't be read from this way: `.get()` returns a zeroed slice
+    /// that gets written over the entire allocation at the next upload, so
+    /// every item in it has to be written, even when only some of them
+    /// changed.
+    ///
+    /// # Panics
+    ///
+    /// If the size of `T` is not a multiple of `wgpu::COPY_BUFFER_ALIGNMENT`,
+    /// as allocations could not be copied on the GPU.
+    pub fn without_mirror(usages: wgpu::BufferUsages, device: &wgpu::Device) -> Self {
+        assert!(
+            core::mem::size_of::<T>().is_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT as usize),
+            "The size of T must be a multiple of wgpu::COPY_BUFFER_ALIGNMENT without a mirror"
+        );
+
+        Self::create(usages, device, false)
+    }
+
+    /// Whether a copy of the buffer is kept on the CPU, see
+    /// `SimpleGpuMemory::without_mirror()`
+    pub fn has_mirror(&self) -> bool {
+        self.mirrored
+    }
+
+    fn create(usages: wgpu::BufferUsages, device: &wgpu::Device, mirrored: bool) -> Self {
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("wgpu_text Buffer Allocator"),
+            size: core::mem::size_of::<T>() as wgpu::BufferAddress,
+            usage: usages | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            buffer,
+            data: Vec::new(),
+            end: 0,
+            mirrored,
+            staged: SecondaryMap::new(),
+            pending_moves: Vec::new(),
+            available_ranges: Vec::new(),
+            used_ranges: SlotMap::new(),
+            address_versions: Versions::default(),
+            allocated_count: 0,
+            counters: Counters::default(),
+            groups: SlotMap::with_key(),
+            group_versions: Versions::default(),
+            group_of: SecondaryMap::new(),
+            compact_on_upload: true,
+            dirty: DirtyRanges::default(),
+            pending: SecondaryMap::new(),
+            priorities: SecondaryMap::new(),
+            allocated_at: SecondaryMap::new(),
+            allocations_made: 0,
+            labels: SecondaryMap::new(),
+            on_leak: OnLeak::default(),
+            poison: None,
+            poisoned: Vec::new(),
+            #[cfg(debug_assertions)]
+            freed_at: SecondaryMap::new(),
+            #[cfg(feature = "backtraces")]
+            backtraces: SecondaryMap::new(),
+            #[cfg(feature = "verify")]
+            verify_every: 0,
+            #[cfg(feature = "verify")]
+            uploads_since_verify: 0,
+            mutated: false,
+            _phantom: Default::default(),
+        }
+    }
+
+    /// Create a new group to allocate memory in with `.allocate_in()`
+    pub fn create_group(&mut self) -> GroupId {
+        self.group_versions
+            .insert(&mut self.groups, Group::default())
+    }
+
+    /// Allocate `count * size_of::<T>()` bytes in the buffer as part of
+    /// `group`, so it gets freed by `.free_group(group)`. The allocation can
+    /// still be resized or freed on its own like any other allocation.
+    pub fn allocate_in(&mut self, group: GroupId, count: usize) -> AddressId {
+        let index = self.allocate(count);
+
+        self.group_of.insert(index, group);
+
+        let group = &mut self.groups[group];
+        group.members.push(index);
+        group.len += 1;
+
+        index
+    }
 
-    mutated: bool,
-    _phantom: PhantomData<T>,
-}
+    /// Label the allocation at `index`, like `.allocate_labeled()` does, for
+    /// example to label an allocation made with `.allocate_in()`
+    pub fn set_label(&mut self, index: &AddressId, label: impl Into<Label>) {
+        self.labels.insert(*index, label.into());
+    }
 
-impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> SimpleGpuMemory<T> {
-    fn merge_available_ranges(&mut self, index: usize) {
-        while index + 1 < self.available_ranges.len()
-            && self.available_ranges[index].end >= self.available_ranges[index + 1].start
-        {
-            let right = self.available_ranges.remove(index + 1);
-            let left = &mut self.available_ranges[index];
+    /// Fill memory with `pattern` whenever it's freed, both on the CPU and on
+    /// the GPU, so shaders that still read from freed allocations stand out,
+    /// for example with `f32::NAN.to_bits()` or `0xDEADBEEF`. The pattern
+    /// repeats every 4 bytes from the start of the buffer. The end of the
+    /// buffer left free by compacting gets poisoned as well, and new
+    /// allocations start out poisoned when they're made in freed memory.
+    /// `None` turns this off, which is the default.
+    ///
+    /// The GPU gets the poison at the next upload, `.upload_with_budget()`
+    /// included, where it doesn't count towards the budget.
+    pub fn set_poison(&mut self, pattern: Option<u32>) {
+        self.poison = pattern;
+    }
 
-            left.start = left.start.min(right.start);
-            left.end = left.end.max(right.end);
+    /// Choose what happens when the memory is dropped while there are still
+    /// allocations in it, by default nothing happens
+    pub fn set_on_leak(&mut self, on_leak: OnLeak) {
+        self.on_leak = on_leak;
+    }
+
+    /// Every allocation that's alive right now, which is what gets reported
+    /// as leaked if the memory is dropped
+    pub fn leaks(&self) -> LeakReport {
+        let leaks = self
+            .used_ranges
+            .iter()
+            .map(|(index, range)| Leak {
+                index,
+                size: range.len(),
+                label: self.labels.get(index).cloned(),
+                group: self.group_of.get(index).copied(),
+                #[cfg(feature = "backtraces")]
+                backtrace: self.backtraces.get(index).cloned(),
+            })
+            .sorted_by_key(|leak| core::cmp::Reverse(leak.size))
+            .collect();
+
+        LeakReport { leaks }
+    }
+
+    /// Deallocate every allocation in `group` at once, this is a lot cheaper
+    /// than calling `.free()` for each of them
+    #[track_caller]
+    pub fn free_group(&mut self, group: GroupId) {
+        let Some(members) = self.groups.remove(group) else {
+            return;
+        };
+
+        self.mutated = true;
+
+        let ranges = members
+            .members
+            .into_iter()
+            .filter_map(|index| {
+                // Freed on its own already, maybe with its slot reused since
+                if self.group_of.remove(index) != Some(group) {
+                    return None;
+                }
+
+                self.staged.remove(index);
+                self.pending.remove(index);
+                self.priorities.remove(index);
+                self.allocated_at.remove(index);
+                self.labels.remove(index);
+                #[cfg(feature = "backtraces")]
+                self.backtraces.remove(index);
+                #[cfg(debug_assertions)]
+                self.freed_at.insert(index, Location::caller());
+                self.used_ranges.remove(index)
+            })
+            .collect::<Vec<_>>();
+
+        self.allocated_count -=
+            ranges.iter().map(|range| range.len()).sum::<usize>() / core::mem::size_of::<T>();
+
+        self.make_ranges_available(ranges);
+    }
+
+    /// The amount of allocations in `group`
+    pub fn group_len(&self, group: GroupId) -> usize {
+        self.groups.get(group).map(|group| group.len).unwrap_or(0)
+    }
+
+    /// Whether `.upload()` removes all the holes left by freed memory before
+    /// uploading, this is enabled by default. When disabled, the holes get
+    /// uploaded too and can be removed gradually with `.defragment()`.
+    pub fn set_compact_on_upload(&mut self, compact_on_upload: bool) {
+        self.compact_on_upload = compact_on_upload;
+    }
+
+    /// The offset in bytes of the allocated memory at `index` in the buffer,
+    /// this changes when the buffer gets compacted or optimized
+    #[track_caller]
+    pub fn offset(&self, index: &AddressId) -> wgpu::BufferAddress {
+        self.range_of(index).start as wgpu::BufferAddress
+    }
+
+    /// The fraction of the buffer that's taken up by holes left by freed
+    /// memory, from 0 (no holes) to 1 (nothing allocated)
+    pub fn fragmentation(&self) -> f32 {
+        if self.end == 0 {
+            return 0.0;
         }
+
+        1.0 - self.size() as f32 / self.end as f32
     }
 
-    fn make_range_available(&mut self, range: AddressRange) {
-        if let Some(other_range_index) = self
-            .available_ranges
+    /// The layout of the buffer right now, to export as an image with
+    /// `.to_svg()` or `.to_ppm()`. Allocations restored from a `Snapshot`
+    /// count as the oldest in the buffer.
+    pub fn memory_map(&self) -> MemoryMap {
+        let allocations = self
+            .used_ranges
             .iter()
-            .position(|other_range| other_range.start <= range.end)
-        {
-            self.available_ranges.insert(other_range_index, range);
-            self.merge_available_ranges(other_range_index);
-        } else {
-            self.available_ranges.push(range);
+            .map(|(index, range)| MappedAllocation {
+                index,
+                range: range.clone(),
+                group: self.group_of.get(index).copied(),
+                label: self.labels.get(index).cloned(),
+                age: self
+                    .allocations_made
+                    .saturating_sub(self.allocated_at.get(index).map_or(0, |at| at + 1)),
+            })
+            .sorted_by_key(|allocation| allocation.range.start)
+            .collect();
+
+        MemoryMap {
+            size: self.end,
+            allocations,
+            holes: self.available_ranges.clone(),
         }
     }
 
-    /// Remove all the holes between memory segments
-    fn fix_sequence(&mut self) {
-        for range in self.available_ranges.drain(..).rev() {
-            let range_len = range.len();
+    /// Move allocations towards the front of the buffer to fill the holes
+    /// left by freed memory, moving at most `max_bytes` bytes. This spreads
+    /// out the cost of compacting the buffer over multiple calls, for example
+    /// once every frame until `.fragmentation()` is low enough. Allocations
+    /// bigger than `max_bytes` are never moved.
+    ///
+    /// Returns the allocations that were moved, their `AddressId` stays the
+    /// same but their offset in the buffer has changed.
+    pub fn defragment(&mut self, max_bytes: usize) -> Vec<AddressId> {
+        let Some(first_hole) = self.available_ranges.first().map(|range| range.start) else {
+            return Vec::new();
+        };
+
+        // The dirty ranges would be left behind by the moves
+        self.dirty_to_pending();
+
+        let allocations = self
+            .used_ranges
+            .iter()
+            .filter(|(_, range)| range.start >= first_hole)
+            .map(|(index, range)| (index, range.clone()))
+            .sorted_by_key(|(_, range)| range.start)
+            .collect::<Vec<_>>();
+
+        let mut budget = max_bytes;
+        let mut cursor = first_hole;
+        let mut moved = Vec::new();
+        let mut moves = Vec::new();
+
+        for (index, range) in allocations {
+            if range.start != cursor {
+                if range.len() > budget {
+                    // Leave it where it is, but smaller allocations after it
+                    // can still be moved into the holes after it
+                    cursor = range.end;
+                    continue;
+                }
+
+                budget -= range.len();
 
-            for used_range in self.used_ranges.values_mut() {
-                if range.end <= used_range.start {
-                    used_range.start -= range_len;
-                    used_range.end -= range_len;
+                if self.mirrored {
+                    self.data.copy_within(range.clone(), cursor);
                 }
+
+                self.used_ranges[index] = cursor..(cursor + range.len());
+                self.dirty.insert(self.used_ranges[index].clone());
+                moved.push(index);
+                moves.push(Move {
+                    source: range.start,
+                    destination: cursor,
+                    len: range.len(),
+                });
+            }
+
+            cursor += range.len();
+        }
+
+        if !moved.is_empty() {
+            let old_end = self.end;
+
+            self.mutated = true;
+            self.push_moves(moves);
+            self.rebuild_available_ranges();
+            self.poison_after_compacting(old_end);
+
+            // Where the moved allocations were is free now
+            for hole in self.available_ranges.clone() {
+                self.poison_range(&hole);
+            }
+        }
+
+        moved
+    }
+
+    /// Find all the holes between allocations, dropping the unused memory at
+    /// the end of the buffer
+    fn rebuild_available_ranges(&mut self) {
+        let mut end = 0;
+
+        self.available_ranges.clear();
+
+        for range in self.used_ranges.values().sorted_by_key(|range| range.start) {
+            if range.start > end {
+                self.available_ranges.push(end..range.start);
             }
 
-            self.data.drain(range);
+            end = end.max(range.end);
         }
+
+        self.end = end;
+        self.data.truncate(end);
     }
 
-    fn sort(&mut self, descending: bool) {
+    fn sort(&mut self, descending: bool) -> Vec<Move> {
         fn _sort_asc((_key, range): &(DefaultKey, &AddressRange)) -> isize {
             range.len() as isize
         }
@@ -81,18 +1556,37 @@ impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> SimpleGpuMemory<T>
             .sorted_by_key(if descending { _sort_desc } else { _sort_asc })
             .collect::<Vec<_>>();
 
-        let mut new_data = Vec::with_capacity(self.size());
+        let mut new_data = Vec::with_capacity(if self.mirrored { self.size() } else { 0 });
+        let mut moves = Vec::new();
+        let mut end = 0;
 
         for (key, range) in sorted_ranges {
-            let start = new_data.len();
-            new_data.extend(&self.data[range.to_owned()]);
-            let end = new_data.len();
+            let start = end;
+            end += range.len();
+
+            if self.mirrored {
+                new_data.extend(&self.data[range.to_owned()]);
+            }
+
+            if range.start != start {
+                moves.push(Move {
+                    source: range.start,
+                    destination: start,
+                    len: range.len(),
+                });
+            }
 
             self.used_ranges[key] = start..end;
         }
 
+        let old_end = self.end;
+
         self.data = new_data;
+        self.end = end;
         self.available_ranges.clear();
+        self.poison_after_compacting(old_end);
+
+        moves
     }
 }
 
@@ -133,22 +1627,7 @@ impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> GpuMemory<T> for Si
     type OptimizationStrategy = Strategy;
 
     fn new(usages: wgpu::BufferUsages, device: &wgpu::Device) -> Self {
-        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("wgpu_text Buffer Allocator"),
-            size: core::mem::size_of::<T>() as wgpu::BufferAddress,
-            usage: usages | wgpu::BufferUsages::COPY_DST,
-            mapped_at_creation: false,
-        });
-
-        Self {
-            buffer,
-            data: Vec::new(),
-            available_ranges: Vec::new(),
-            used_ranges: SlotMap::new(),
-            allocated_count: 0,
-            mutated: false,
-            _phantom: Default::default(),
-        }
+        Self::create(usages, device, true)
     }
 
     fn mutated(&self) -> bool {
@@ -181,42 +1660,286 @@ impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> GpuMemory<T> for Si
                 self.available_ranges.remove(range_index)
             }
         } else {
-            let start = self.data.len();
-            self.data.extend((0..size).map(|_| 0));
-            let end = self.data.len();
+            let start = self.end;
+            self.end += size;
+
+            if self.mirrored {
+                self.data.resize(self.end, 0);
+            }
 
-            start..end
+            start..self.end
         };
 
+        self.dirty.insert(range.clone());
+
         self.allocated_count += count;
-        self.used_ranges.insert(range)
+        self.counters
+            .record_size(self.allocated_count * core::mem::size_of::<T>());
+
+        let index = self.address_versions.insert(&mut self.used_ranges, range);
+        self.allocated_at.insert(index, self.allocations_made);
+        self.allocations_made += 1;
+
+        #[cfg(feature = "backtraces")]
+        self.backtraces
+            .insert(index, Arc::new(Backtrace::force_capture()));
+
+        index
+    }
+
+    fn allocate_labeled(&mut self, count: usize, label: impl Into<Label>) -> Self::Index {
+        let index = self.allocate(count);
+        let label = label.into();
+
+        log::trace!(
+            "Allocated {} for {label:?} at {}",
+            format_size(self.used_ranges[index].len(), DECIMAL),
+            self.used_ranges[index].start
+        );
+
+        self.labels.insert(index, label);
+
+        index
+    }
+
+    fn label_of(&self, index: &Self::Index) -> Option<Label> {
+        self.labels.get(*index).cloned()
+    }
+
+    /// # Panics
+    ///
+    /// Without a mirror, as there is no copy of the buffer on the CPU to read
+    /// from. Use `.read_back()` instead.
+    #[track_caller]
+    fn get_ref(&self, index: &Self::Index) -> &[T] {
+        assert!(self.mirrored, "{NO_MIRROR_READ}");
+
+        bytemuck::cast_slice(&self.data[self.range_of(index).clone()])
+    }
+
+    /// # Panics
+    ///
+    /// Without a mirror, like `.get_ref()`
+    fn iter(&self) -> impl Iterator<Item = (Self::Index, &[T])> + '_ {
+        assert!(self.mirrored, "{NO_MIRROR_READ}");
+
+        self.used_ranges
+            .iter()
+            .map(|(index, range)| (index, bytemuck::cast_slice(&self.data[range.clone()])))
+    }
+
+    #[track_caller]
+    fn get_many_mut<const N: usize>(
+        &mut self,
+        indices: [&Self::Index; N],
+    ) -> Result<[&mut [T]; N], GetManyMutError> {
+        let ranges = indices.map(|index| self.range_of(index).clone());
+
+        if !self.mirrored {
+            disjoint_order(indices, &ranges)?;
+
+            for (index, range) in indices.iter().zip(&ranges) {
+                self.staged
+                    .entry(**index)
+                    .unwrap()
+                    .or_insert_with(|| Staged::new(range.len()));
+            }
+
+            self.mutated = true;
+
+            let bytes = self
+                .staged
+                .get_disjoint_mut(indices.map(|index| *index))
+                .unwrap();
+
+            return Ok(bytes.map(|staged| staged.items()));
+        }
+
+        let memory = split_many_mut(&mut self.data, indices, &ranges)?;
+
+        for range in ranges {
+            self.dirty.insert(range);
+        }
+
+        self.mutated = true;
+
+        Ok(memory)
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = (Self::Index, &mut [T])> + '_ {
+        self.mutated = true;
+
+        if !self.mirrored {
+            for (index, range) in &self.used_ranges {
+                self.staged
+                    .entry(index)
+                    .unwrap()
+                    .or_insert_with(|| Staged::new(range.len()));
+            }
+
+            return self
+                .staged
+                .iter_mut()
+                .map(|(index, staged)| (index, staged.items()))
+                .collect::<Vec<_>>()
+                .into_iter();
+        }
+
+        for range in self.used_ranges.values() {
+            self.dirty.insert(range.clone());
+        }
+
+        let allocations = self
+            .used_ranges
+            .iter()
+            .map(|(index, range)| (index, range.clone()));
+
+        split_all_mut(&mut self.data, allocations).into_iter()
+    }
+
+    #[track_caller]
+    fn write(&mut self, index: &Self::Index, offset: usize, data: &[T]) {
+        let size = core::mem::size_of::<T>();
+        let written = (offset * size)..((offset + data.len()) * size);
+
+        self.write_with(index, written, |bytes| {
+            bytes.copy_from_slice(bytemuck::cast_slice(data))
+        });
+    }
+
+    #[track_caller]
+    fn fill(&mut self, index: &Self::Index, value: T) {
+        let len = self.range_of(index).len();
+
+        self.write_with(index, 0..len, |bytes| {
+            bytemuck::cast_slice_mut::<u8, T>(bytes).fill(value)
+        });
+    }
+
+    #[track_caller]
+    fn set(&mut self, index: &Self::Index, i: usize, value: T) {
+        let size = core::mem::size_of::<T>();
+
+        self.write_with(index, (i * size)..((i + 1) * size), |bytes| {
+            bytes.copy_from_slice(bytemuck::bytes_of(&value))
+        });
+    }
+
+    /// Without a mirror, the memory is copied on the GPU at the next upload,
+    /// followed by anything written to `source` with `.get()` before this
+    #[track_caller]
+    fn copy_allocation(&mut self, source: &Self::Index, destination: &Self::Index) {
+        let source_range = self.range_of(source).clone();
+        let destination_range = self.range_of(destination).clone();
+
+        assert_eq!(
+            source_range.len(),
+            destination_range.len(),
+            "Copying {source:?} to {destination:?}, which have different lengths"
+        );
+
+        if source == destination {
+            return;
+        }
+
+        self.mutated = true;
+
+        if self.mirrored {
+            self.data.copy_within(source_range, destination_range.start);
+            self.dirty.insert(destination_range);
+
+            return;
+        }
+
+        self.push_moves(vec![Move {
+            source: source_range.start,
+            destination: destination_range.start,
+            len: source_range.len(),
+        }]);
+        self.unpoison(&destination_range);
+
+        // Anything written to `destination` so far is copied over
+        match self.staged.get(*source).cloned() {
+            Some(staged) => self.staged.insert(*destination, staged),
+            None => self.staged.remove(*destination),
+        };
     }
 
     fn len(&self) -> usize {
         self.allocated_count
     }
 
+    /// Without a mirror, this returns a zeroed slice that gets written over
+    /// the entire allocation at the next upload, see
+    /// `SimpleGpuMemory::without_mirror()`
+    #[track_caller]
     fn get(&mut self, index: &Self::Index) -> &mut [T] {
         self.mutated = true;
 
-        let range = &self.used_ranges[*index];
+        let range = self.range_of(index).clone();
+
+        if !self.mirrored {
+            let len = range.len();
+            let staged = self
+                .staged
+                .entry(*index)
+                .unwrap()
+                .or_insert_with(|| Staged::new(len));
+
+            return staged.items();
+        }
+
+        self.dirty.insert(range.clone());
 
         bytemuck::cast_slice_mut(&mut self.data[range.start..range.end])
     }
 
+    #[track_caller]
     fn len_of(&self, index: &Self::Index) -> usize {
-        self.used_ranges[*index].len() / core::mem::size_of::<T>()
+        self.range_of(index).len() / core::mem::size_of::<T>()
     }
 
+    fn contains(&self, index: &Self::Index) -> bool {
+        self.used_ranges.contains_key(*index)
+    }
+
+    #[track_caller]
     fn resize(&mut self, index: &mut Self::Index, len: usize) {
         let size = len * core::mem::size_of::<T>();
 
-        let range = self.used_ranges[*index].clone();
+        let range = self.range_of(index).clone();
 
-        match self.used_ranges[*index].len().cmp(&size) {
+        match range.len().cmp(&size) {
             Ordering::Less => {
+                let group = self.group_of.get(*index).copied();
+                let priority = self.priorities.get(*index).copied();
+                let allocated_at = self.allocated_at.get(*index).copied();
+                let label = self.labels.remove(*index);
+                #[cfg(feature = "backtraces")]
+                let backtrace = self.backtraces.get(*index).cloned();
+
                 self.free(*index);
-                *index = self.allocate(len);
+                *index = match group {
+                    Some(group) => self.allocate_in(group, len),
+                    None => self.allocate(len),
+                };
+
+                if let Some(priority) = priority {
+                    self.priorities.insert(*index, priority);
+                }
+
+                if let Some(allocated_at) = allocated_at {
+                    self.allocated_at.insert(*index, allocated_at);
+                }
+
+                if let Some(label) = label {
+                    self.labels.insert(*index, label);
+                }
+
+                #[cfg(feature = "backtraces")]
+                if let Some(backtrace) = backtrace {
+                    self.backtraces.insert(*index, backtrace);
+                }
             }
             Ordering::Equal => (),
             Ordering::Greater => {
@@ -227,15 +1950,65 @@ impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> GpuMemory<T> for Si
                 self.make_range_available(free_range);
 
                 self.used_ranges[*index].start = range.end - size;
+
+                if let Some(staged) = self.staged.get_mut(*index) {
+                    staged.bytes.drain(..(range.len() - size));
+                    staged.written.cut_front(range.len() - size);
+                }
             }
         }
+
+        if let Some(label) = self.labels.get(*index) {
+            log::trace!(
+                "Resized {label:?} from {} to {}",
+                format_size(range.len(), DECIMAL),
+                format_size(size, DECIMAL)
+            );
+        }
     }
 
+    #[track_caller]
     fn free(&mut self, index: Self::Index) {
         self.mutated = true;
 
+        #[cfg(debug_assertions)]
+        if let Some(location) = self.freed_at.get(index) {
+            log::warn!("{index:?} was freed twice, it was already freed at {location}");
+        }
+
         if let Some(range) = self.used_ranges.remove(index) {
+            #[cfg(debug_assertions)]
+            self.freed_at.insert(index, Location::caller());
+
             self.allocated_count -= range.len() / core::mem::size_of::<T>();
+            self.staged.remove(index);
+            self.pending.remove(index);
+            self.priorities.remove(index);
+            self.allocated_at.remove(index);
+            #[cfg(feature = "backtraces")]
+            self.backtraces.remove(index);
+
+            if let Some(label) = self.labels.remove(index) {
+                log::trace!("Freed {} of {label:?}", format_size(range.len(), DECIMAL));
+            }
+
+            if let Some(group) = self
+                .group_of
+                .remove(index)
+                .and_then(|group| self.groups.get_mut(group))
+            {
+                group.len -= 1;
+
+                // Only drop freed allocations once they make up most of the
+                // group, so freeing them one by one stays cheap
+                if group.members.len() > 2 * group.len.max(16) {
+                    let group_of = &self.group_of;
+
+                    group
+                        .members
+                        .retain(|member| group_of.contains_key(*member));
+                }
+            }
 
             self.make_range_available(range);
         }
@@ -246,22 +2019,72 @@ impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> GpuMemory<T> for Si
             return;
         }
 
-        self.fix_sequence();
+        if !self.mirrored {
+            if self.compact_on_upload {
+                let moves = self.fix_sequence();
+                self.push_moves(moves);
+            }
+
+            let size = self.buffer.size().max(self.end as u64);
+
+            self.sync_gpu_layout(queue, device, size);
+            self.flush_staged(queue);
+
+            self.dirty.clear();
+            self.mutated = false;
+
+            return;
+        }
+
+        let moved = self.compact_on_upload && !self.fix_sequence().is_empty();
+
+        if moved || self.buffer.size() < self.data.len() as u64 {
+            upload_or_resize(queue, device, &mut self.buffer, &self.data);
+            self.counters.record_upload(self.data.len());
+        } else {
+            // Nothing moved, so only the memory that changed is uploaded
+            self.pending_to_dirty();
+            self.dirty.truncate(self.data.len());
+
+            let written = write_ranges(queue, &self.buffer, &self.data, self.dirty.ranges());
+            self.counters.record_upload(written);
+        }
 
-        upload_or_resize(queue, device, &mut self.buffer, &self.data);
+        self.flush_poison(queue);
 
+        self.dirty.clear();
+        self.pending.clear();
         self.mutated = false;
+
+        #[cfg(feature = "verify")]
+        if self.verify_every > 0 {
+            self.uploads_since_verify += 1;
+
+            if self.uploads_since_verify >= self.verify_every {
+                self.uploads_since_verify = 0;
+
+                if let Err(err) = self.verify(queue, device) {
+                    panic!("{err}");
+                }
+            }
+        }
     }
 
     fn optimize(
         &mut self,
         strategy: Self::OptimizationStrategy,
-        _queue: &wgpu::Queue,
+        queue: &wgpu::Queue,
         device: &wgpu::Device,
     ) {
         let size = self.size();
 
         match strategy {
+            Strategy::Truncate if !self.mirrored => {
+                let moves = self.fix_sequence();
+                self.push_moves(moves);
+
+                self.sync_gpu_layout(queue, device, size as u64);
+            }
             Strategy::Truncate => {
                 self.fix_sequence();
 
@@ -276,6 +2099,9 @@ impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> GpuMemory<T> for Si
                     usage: self.buffer.usage() | wgpu::BufferUsages::COPY_DST,
                     contents: &self.data,
                 });
+                self.counters.record_upload(self.data.len());
+                self.dirty.clear();
+                self.pending.clear();
 
                 let capacity_before = self.data.capacity();
 
@@ -289,12 +2115,91 @@ impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> GpuMemory<T> for Si
                     );
                 }
             }
-            Strategy::SortSizeDescending => {
-                self.sort(true);
+            Strategy::SortSizeDescending | Strategy::SortSizeAscending => {
+                let moves = self.sort(matches!(strategy, Strategy::SortSizeDescending));
+                self.push_moves(moves);
+
+                // The moves are only made on the CPU with a mirror, so all of
+                // it has to be uploaded again
+                if self.mirrored {
+                    self.dirty.insert(0..self.end);
+                }
+
+                self.mutated = true;
+            }
+        }
+    }
+
+    /// Without a mirror, the contents of the buffer are lost, so it is
+    /// recreated zeroed. Memory written since the last upload still gets
+    /// written at the next upload.
+    fn recreate(&mut self, queue: &wgpu::Queue, device: &wgpu::Device) {
+        self.buffer = recreate_buffer(
+            queue,
+            device,
+            "wgpu_text Buffer Allocator",
+            self.buffer.usage(),
+            &self.data,
+            (core::mem::size_of::<T>() as u64).max(self.end as u64),
+        );
+        self.counters.record_upload(self.data.len());
+
+        self.pending_moves.clear();
+        self.dirty.clear();
+        self.pending.clear();
+        self.mutated = !self.staged.is_empty();
+    }
+
+    /// With a mirror, `write` writes to the mirror first, which then gets
+    /// copied to the staging memory. This is still cheaper than `.get()`, as
+    /// only this allocation gets uploaded.
+    fn write_buffer_with(
+        &mut self,
+        index: &Self::Index,
+        queue: &wgpu::Queue,
+        device: &wgpu::Device,
+        write: impl FnOnce(&mut [T]),
+    ) {
+        // Writes have to land where the allocation is on the GPU right now
+        if !self.flush_dirty(queue, device) {
+            // Either the buffer is too small or allocations can't be written
+            // with `queue.write_buffer_with()`, see `.flush_dirty()`
+            self.upload(queue, device);
+
+            if !core::mem::size_of::<T>().is_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT as usize) {
+                return write(self.get(index));
+            }
+        }
+
+        let range = self.range_of(index).clone();
+
+        let Some(size) = wgpu::BufferSize::new(range.len() as u64) else {
+            return;
+        };
+
+        let view = queue.write_buffer_with(&self.buffer, range.start as u64, size);
+
+        match (self.mirrored, view) {
+            (true, view) => {
+                let mirror = &mut self.data[range.clone()];
+                write(bytemuck::cast_slice_mut(mirror));
+
+                match view {
+                    Some(mut view) => {
+                        view.copy_from_slice(mirror);
+                        self.counters.record_upload(mirror.len());
+                    }
+                    None => {
+                        self.dirty.insert(range);
+                        self.mutated = true;
+                    }
+                }
             }
-            Strategy::SortSizeAscending => {
-                self.sort(false);
+            (false, Some(mut view)) => {
+                write(bytemuck::cast_slice_mut(&mut view));
+                self.counters.record_upload(size.get() as usize);
             }
+            (false, None) => (),
         }
     }
 
@@ -302,7 +2207,48 @@ impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> GpuMemory<T> for Si
         &self.buffer
     }
 
-    fn buffer_slice(&self) -> wgpu::BufferSlice {
-        self.buffer.slice(..(self.size() as u64))
+    fn buffer_slice(&self) -> wgpu::BufferSlice<'_> {
+        self.buffer.slice(..(self.end as u64))
+    }
+
+    /// Free ranges are the holes left by freed memory, the buffer can always
+    /// grow at the end. Without a mirror, the memory written with `.get()`
+    /// that's waiting for the next upload counts as CPU capacity.
+    fn stats(&self) -> Stats {
+        let cpu_capacity = match self.mirrored {
+            true => self.data.capacity(),
+            false => self
+                .staged
+                .values()
+                .map(|staged| staged.bytes.capacity())
+                .sum(),
+        };
+
+        let mut stats = Stats::new(
+            self.used_ranges.len(),
+            self.available_ranges.iter().map(|range| range.len()),
+            &self.buffer,
+            cpu_capacity,
+            &self.counters,
+        );
+
+        for (index, label) in &self.labels {
+            let label_stats = stats.labels.entry(label.clone()).or_default();
+
+            label_stats.allocations += 1;
+            label_stats.size += self.used_ranges[index].len();
+        }
+
+        stats
+    }
+}
+
+impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> Drop for SimpleGpuMemory<T> {
+    fn drop(&mut self) {
+        if self.used_ranges.is_empty() || matches!(self.on_leak, OnLeak::Ignore) {
+            return;
+        }
+
+        self.on_leak.handle(&self.leaks());
     }
 }
@@ -0,0 +1,548 @@
+use std::{marker::PhantomData, ops::Range};
+
+use humansize::{format_size, DECIMAL};
+use slotmap::SecondaryMap;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    recreate_buffer, split_all_mut, split_many_mut,
+    stats::{Counters, Stats},
+    tlsf::{BlockId, Tlsf},
+    upload_or_resize, GetManyMutError, GpuMemory,
+};
+
+/// The biggest allocation in items that gets a slot in a slab, bigger
+/// allocations get their own range in the buffer
+pub const MAX_SLOT_SIZE: usize = 64;
+
+const CLASS_COUNT: usize = MAX_SLOT_SIZE.ilog2() as usize + 1;
+/// The amount of slots in a slab, one bit for each slot in the `occupied` mask
+const SLOTS_PER_SLAB: usize = u32::BITS as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Location {
+    Slot {
+        class: u8,
+        slot: u32,
+        generation: u16,
+    },
+    Range(BlockId),
+}
+
+/// An index to an allocation in a `SlabGpuMemory`. This stores the location
+/// and length of the allocation itself, so small allocations don't need any
+/// bookkeeping besides a free list, their length for `.iter()` and the
+/// generation of their slot to tell freed addresses apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SlabAddress {
+    location: Location,
+    len: usize,
+}
+
+/// All slabs with slots of `1 << class` items
+#[derive(Debug, Default)]
+struct SizeClass {
+    /// The block in the buffer of each slab, `None` if it was released by
+    /// `.optimize()`
+    slabs: Vec<Option<BlockId>>,
+    /// A mask of the slots in use for each slab
+    occupied: Vec<u32>,
+    free_slots: Vec<u32>,
+    /// The length of the allocation in each slot, for `.iter()`
+    lens: Vec<u8>,
+    /// How often each slot was freed, wrapping around, so addresses of freed
+    /// allocations can be told apart from newer ones in the same slot
+    generations: Vec<u16>,
+}
+
+impl SizeClass {
+    /// If `slot` is in use by the allocation with `generation`
+    fn is_live(&self, slot: u32, generation: u16) -> bool {
+        let mask = 1 << (slot as usize % SLOTS_PER_SLAB);
+
+        self.occupied
+            .get(slot as usize / SLOTS_PER_SLAB)
+            .is_some_and(|occupied| occupied & mask != 0)
+            && self.generations[slot as usize] == generation
+    }
+}
+
+/// A slab allocator for lots of small allocations. Allocations of up to
+/// `MAX_SLOT_SIZE` items are rounded up to a power of two and get a slot in a
+/// slab, a run of equally sized slots in the buffer, which makes allocating
+/// and freeing them O(1) without any per-allocation bookkeeping. Bigger
+/// allocations fall back to a range allocator, the same one used by
+/// `TlsfGpuMemory`. Allocations never move, so their offset in the buffer can
+/// be relied on until they are freed.
+#[derive(Debug)]
+pub struct SlabGpuMemory<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> {
+    buffer: wgpu::Buffer,
+    data: Vec<u8>,
+    tlsf: Tlsf,
+    classes: [SizeClass; CLASS_COUNT],
+    /// The length of every allocation with its own range, for `.iter()`
+    ranges: SecondaryMap<BlockId, usize>,
+    allocated_count: usize,
+    /// The amount of live allocations, as they aren't stored anywhere
+    allocation_count: usize,
+    counters: Counters,
+
+    mutated: bool,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> SlabGpuMemory<T> {
+    /// The offset in bytes of the allocated memory at `index` in the buffer,
+    /// this does not change until the memory is freed or resized
+    pub fn offset(&self, index: &SlabAddress) -> wgpu::BufferAddress {
+        self.byte_range(index).start as wgpu::BufferAddress
+    }
+
+    /// The amount of items the buffer can hold before it has to grow
+    pub fn capacity(&self) -> usize {
+        self.tlsf.capacity()
+    }
+
+    fn class_of(count: usize) -> usize {
+        count.max(1).next_power_of_two().trailing_zeros() as usize
+    }
+
+    fn byte_range(&self, index: &SlabAddress) -> Range<usize> {
+        let offset = match index.location {
+            Location::Slot {
+                class,
+                slot,
+                generation,
+            } => {
+                let size_class = &self.classes[class as usize];
+
+                assert!(size_class.is_live(slot, generation), "{index:?} was freed");
+
+                let slot = slot as usize;
+                let slab =
+                    size_class.slabs[slot / SLOTS_PER_SLAB].expect("Slot in a released slab");
+
+                self.tlsf.offset(slab) + ((slot % SLOTS_PER_SLAB) << class)
+            }
+            Location::Range(block) => self.tlsf.offset(block),
+        };
+
+        let start = offset * core::mem::size_of::<T>();
+
+        start..(start + index.len * core::mem::size_of::<T>())
+    }
+
+    /// Make the CPU buffer fit the capacity of the allocator
+    fn fit_data(&mut self) {
+        let size = self.tlsf.capacity() * core::mem::size_of::<T>();
+
+        if self.data.len() != size {
+            log::trace!(
+                "Resizing slab allocator of size {} to {}",
+                format_size(self.data.len(), DECIMAL),
+                format_size(size, DECIMAL)
+            );
+
+            self.data.resize(size, 0);
+        }
+    }
+
+    /// Add a new slab to `class`, adding its slots to the free list
+    fn create_slab(&mut self, class: usize) {
+        let block = self.tlsf.allocate(SLOTS_PER_SLAB << class);
+        self.fit_data();
+
+        let size_class = &mut self.classes[class];
+
+        let slab = match size_class.slabs.iter().position(Option::is_none) {
+            Some(slab) => {
+                size_class.slabs[slab] = Some(block);
+                slab
+            }
+            None => {
+                size_class.slabs.push(Some(block));
+                size_class.occupied.push(0);
+                size_class
+                    .lens
+                    .resize(size_class.slabs.len() * SLOTS_PER_SLAB, 0);
+                size_class
+                    .generations
+                    .resize(size_class.slabs.len() * SLOTS_PER_SLAB, 0);
+                size_class.slabs.len() - 1
+            }
+        };
+
+        // Reversed so the first slot of the slab gets used first
+        size_class.free_slots.extend(
+            (0..SLOTS_PER_SLAB)
+                .rev()
+                .map(|i| (slab * SLOTS_PER_SLAB + i) as u32),
+        );
+    }
+
+    /// Release every slab that has no slots in use
+    fn release_empty_slabs(&mut self) {
+        for size_class in self.classes.iter_mut() {
+            for (slab, block) in size_class.slabs.iter_mut().enumerate() {
+                if size_class.occupied[slab] != 0 {
+                    continue;
+                }
+
+                if let Some(block) = block.take() {
+                    self.tlsf.free(block);
+                }
+            }
+
+            let slabs = &size_class.slabs;
+
+            size_class
+                .free_slots
+                .retain(|slot| slabs[*slot as usize / SLOTS_PER_SLAB].is_some());
+        }
+    }
+}
+
+/// - `Truncate`: release slabs without any allocations in them, and shrink
+///   the buffer to cut off the unallocated memory at the end of it.
+///   Allocations never move, so this can only free memory at the end of the
+///   buffer.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Strategy {
+    #[default]
+    Truncate,
+}
+
+impl core::fmt::Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Strategy::Truncate => "Truncate",
+            }
+        )
+    }
+}
+
+impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> GpuMemory<T> for SlabGpuMemory<T> {
+    type Index = SlabAddress;
+    type OptimizationStrategy = Strategy;
+
+    fn new(usages: wgpu::BufferUsages, device: &wgpu::Device) -> Self {
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("wgpu_text Slab Allocator"),
+            size: core::mem::size_of::<T>() as wgpu::BufferAddress,
+            usage: usages | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            buffer,
+            data: Vec::new(),
+            tlsf: Tlsf::new(),
+            classes: Default::default(),
+            ranges: SecondaryMap::new(),
+            allocated_count: 0,
+            allocation_count: 0,
+            counters: Counters::default(),
+            mutated: false,
+            _phantom: Default::default(),
+        }
+    }
+
+    fn mutated(&self) -> bool {
+        self.mutated
+    }
+
+    fn allocate(&mut self, count: usize) -> Self::Index {
+        self.mutated = true;
+        self.allocated_count += count;
+        self.allocation_count += 1;
+        self.counters
+            .record_size(self.allocated_count * core::mem::size_of::<T>());
+
+        if count > MAX_SLOT_SIZE {
+            let block = self.tlsf.allocate(count);
+            self.ranges.insert(block, count);
+            self.fit_data();
+
+            return SlabAddress {
+                location: Location::Range(block),
+                len: count,
+            };
+        }
+
+        let class = Self::class_of(count);
+
+        if self.classes[class].free_slots.is_empty() {
+            self.create_slab(class);
+        }
+
+        let size_class = &mut self.classes[class];
+        let slot = size_class.free_slots.pop().unwrap();
+
+        size_class.occupied[slot as usize / SLOTS_PER_SLAB] |=
+            1 << (slot as usize % SLOTS_PER_SLAB);
+        size_class.lens[slot as usize] = count as u8;
+
+        SlabAddress {
+            location: Location::Slot {
+                class: class as u8,
+                slot,
+                generation: size_class.generations[slot as usize],
+            },
+            len: count,
+        }
+    }
+
+    fn get(&mut self, index: &Self::Index) -> &mut [T] {
+        self.mutated = true;
+
+        let range = self.byte_range(index);
+
+        bytemuck::cast_slice_mut(&mut self.data[range])
+    }
+
+    fn get_ref(&self, index: &Self::Index) -> &[T] {
+        bytemuck::cast_slice(&self.data[self.byte_range(index)])
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Self::Index, &[T])> + '_ {
+        let slots = self
+            .classes
+            .iter()
+            .enumerate()
+            .flat_map(|(class, size_class)| {
+                (0..size_class.lens.len())
+                    .filter(|slot| {
+                        size_class.occupied[slot / SLOTS_PER_SLAB] & (1 << (slot % SLOTS_PER_SLAB))
+                            != 0
+                    })
+                    .map(move |slot| SlabAddress {
+                        location: Location::Slot {
+                            class: class as u8,
+                            slot: slot as u32,
+                            generation: size_class.generations[slot],
+                        },
+                        len: size_class.lens[slot] as usize,
+                    })
+            });
+
+        let ranges = self.ranges.iter().map(|(block, len)| SlabAddress {
+            location: Location::Range(block),
+            len: *len,
+        });
+
+        slots.chain(ranges).map(|index| {
+            (
+                index,
+                bytemuck::cast_slice(&self.data[self.byte_range(&index)]),
+            )
+        })
+    }
+
+    fn get_many_mut<const N: usize>(
+        &mut self,
+        indices: [&Self::Index; N],
+    ) -> Result<[&mut [T]; N], GetManyMutError> {
+        let ranges = indices.map(|index| self.byte_range(index));
+        let memory = split_many_mut(&mut self.data, indices, &ranges)?;
+
+        self.mutated = true;
+
+        Ok(memory)
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = (Self::Index, &mut [T])> + '_ {
+        self.mutated = true;
+
+        let allocations = self
+            .iter()
+            .map(|(index, _)| (index, self.byte_range(&index)))
+            .collect::<Vec<_>>();
+
+        split_all_mut(&mut self.data, allocations).into_iter()
+    }
+
+    fn len(&self) -> usize {
+        self.allocated_count
+    }
+
+    fn len_of(&self, index: &Self::Index) -> usize {
+        index.len
+    }
+
+    /// Slots have a generation that wraps around, so an address of an
+    /// allocation freed 65536 times before its slot was used again counts as
+    /// the newer allocation
+    fn contains(&self, index: &Self::Index) -> bool {
+        match index.location {
+            Location::Slot {
+                class,
+                slot,
+                generation,
+            } => self.classes[class as usize].is_live(slot, generation),
+            Location::Range(block) => self.tlsf.is_allocated(block),
+        }
+    }
+
+    fn resize(&mut self, index: &mut Self::Index, len: usize) {
+        let fits = match index.location {
+            Location::Slot { class, .. } => len <= 1 << class,
+            Location::Range(block) => len > MAX_SLOT_SIZE && self.tlsf.resize(block, len),
+        };
+
+        if fits {
+            self.allocated_count = self.allocated_count - index.len + len;
+            self.counters
+                .record_size(self.allocated_count * core::mem::size_of::<T>());
+            index.len = len;
+
+            match index.location {
+                Location::Slot { class, slot, .. } => {
+                    self.classes[class as usize].lens[slot as usize] = len as u8
+                }
+                Location::Range(block) => self.ranges[block] = len,
+            }
+
+            return;
+        }
+
+        let new_index = self.allocate(len);
+
+        let source = self.byte_range(&SlabAddress {
+            len: index.len.min(len),
+            ..*index
+        });
+        let destination = self.byte_range(&new_index).start;
+        self.data.copy_within(source, destination);
+
+        self.free(*index);
+        *index = new_index;
+    }
+
+    fn free(&mut self, index: Self::Index) {
+        match index.location {
+            Location::Slot {
+                class,
+                slot,
+                generation,
+            } => {
+                let size_class = &mut self.classes[class as usize];
+
+                if !size_class.is_live(slot, generation) {
+                    log::warn!("Attempted to free a slot that is not in use");
+                    return;
+                }
+
+                size_class.occupied[slot as usize / SLOTS_PER_SLAB] &=
+                    !(1 << (slot as usize % SLOTS_PER_SLAB));
+                size_class.generations[slot as usize] =
+                    size_class.generations[slot as usize].wrapping_add(1);
+                size_class.free_slots.push(slot);
+            }
+            Location::Range(block) => {
+                if self.ranges.remove(block).is_none() {
+                    log::warn!("Attempted to free a range that is not in use");
+                    return;
+                }
+
+                self.tlsf.free(block);
+            }
+        }
+
+        self.mutated = true;
+        self.allocated_count -= index.len;
+        self.allocation_count -= 1;
+    }
+
+    fn upload(&mut self, queue: &wgpu::Queue, device: &wgpu::Device) {
+        if !self.mutated {
+            return;
+        }
+
+        upload_or_resize(queue, device, &mut self.buffer, &self.data);
+        self.counters.record_upload(self.data.len());
+
+        self.mutated = false;
+    }
+
+    fn optimize(
+        &mut self,
+        strategy: Self::OptimizationStrategy,
+        _queue: &wgpu::Queue,
+        device: &wgpu::Device,
+    ) {
+        match strategy {
+            Strategy::Truncate => {
+                self.release_empty_slabs();
+                self.tlsf.truncate();
+                self.fit_data();
+                self.data.shrink_to_fit();
+
+                self.buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("wgpu_text Resized Buffer"),
+                    usage: self.buffer.usage() | wgpu::BufferUsages::COPY_DST,
+                    contents: &self.data,
+                });
+                self.counters.record_upload(self.data.len());
+
+                self.mutated = false;
+            }
+        }
+    }
+
+    fn recreate(&mut self, queue: &wgpu::Queue, device: &wgpu::Device) {
+        self.buffer = recreate_buffer(
+            queue,
+            device,
+            "wgpu_text Slab Allocator",
+            self.buffer.usage(),
+            &self.data,
+            core::mem::size_of::<T>() as wgpu::BufferAddress,
+        );
+        self.counters.record_upload(self.data.len());
+
+        self.mutated = false;
+    }
+
+    fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    /// Allocations are spread out over the entire buffer, so this returns a
+    /// slice of all the memory managed by the allocator, use `.offset()` to
+    /// find where an allocation is stored in the buffer
+    fn buffer_slice(&self) -> wgpu::BufferSlice<'_> {
+        self.buffer.slice(..(self.data.len() as u64))
+    }
+
+    /// Every free slot counts as a free range of its own, as a slot is the
+    /// most that can be handed out at once from a slab
+    fn stats(&self) -> Stats {
+        let free_slots = self
+            .classes
+            .iter()
+            .enumerate()
+            .flat_map(|(class, size_class)| {
+                core::iter::repeat_n(
+                    (1 << class) * core::mem::size_of::<T>(),
+                    size_class.free_slots.len(),
+                )
+            });
+
+        let free_ranges = self
+            .tlsf
+            .free_blocks()
+            .map(|size| size * core::mem::size_of::<T>())
+            .chain(free_slots);
+
+        Stats::new(
+            self.allocation_count,
+            free_ranges,
+            &self.buffer,
+            self.data.capacity(),
+            &self.counters,
+        )
+    }
+}
@@ -0,0 +1,215 @@
+use std::ops::Range;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use slotmap::{SecondaryMap, SlotMap};
+
+use crate::{
+    simple::{Group, GroupId},
+    AddressId, Label,
+};
+
+/// Written at the start of every snapshot, followed by the format version
+#[cfg(feature = "serde")]
+const MAGIC: [u8; 4] = *b"WGMS";
+#[cfg(feature = "serde")]
+const FORMAT_VERSION: u32 = 1;
+
+/// The state of a `SimpleGpuMemory` at some point, taken by
+/// `SimpleGpuMemory::snapshot()`. Restoring it with
+/// `SimpleGpuMemory::restore()` brings back every allocation as it was, with
+/// the same `AddressId`s.
+///
+/// With the `serde` feature, this implements `Serialize` and `Deserialize`
+/// so it can be stored as part of other data, and `.to_bytes()` and
+/// `Snapshot::from_bytes()` store it as a versioned binary blob.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(transparent))]
+pub struct Snapshot {
+    pub(crate) state: SnapshotState,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub(crate) struct SnapshotState {
+    /// `size_of::<T>()` of the memory the snapshot was taken of
+    pub(crate) item_size: usize,
+    pub(crate) mirrored: bool,
+    pub(crate) data: Vec<u8>,
+    pub(crate) end: usize,
+    pub(crate) available_ranges: Vec<Range<usize>>,
+    pub(crate) used_ranges: SlotMap<AddressId, Range<usize>>,
+    pub(crate) allocated_count: usize,
+    /// Without the allocations that were freed on their own
+    pub(crate) groups: SlotMap<GroupId, Group>,
+    pub(crate) group_of: SecondaryMap<AddressId, GroupId>,
+    pub(crate) priorities: SecondaryMap<AddressId, u32>,
+    pub(crate) labels: SecondaryMap<AddressId, Label>,
+    pub(crate) compact_on_upload: bool,
+}
+
+impl SnapshotState {
+    /// Check that the allocations in the snapshot fit in the memory and don't
+    /// overlap, and that everything refers to allocations and groups that
+    /// exist, so a damaged snapshot can't be restored
+    pub(crate) fn validate(&self) -> Result<(), SnapshotError> {
+        if self.mirrored && self.end > self.data.len() {
+            return Err(SnapshotError::Corrupted);
+        }
+
+        let whole_items = |range: &Range<usize>| range.len().is_multiple_of(self.item_size);
+
+        if !self.used_ranges.values().all(whole_items)
+            || !self.available_ranges.iter().all(whole_items)
+        {
+            return Err(SnapshotError::Corrupted);
+        }
+
+        // Holes are found and merged by their order
+        if !self
+            .available_ranges
+            .windows(2)
+            .all(|ranges| ranges[0].end <= ranges[1].start)
+        {
+            return Err(SnapshotError::Corrupted);
+        }
+
+        let mut ranges = self
+            .used_ranges
+            .values()
+            .chain(&self.available_ranges)
+            .collect::<Vec<_>>();
+        ranges.sort_unstable_by_key(|range| (range.start, range.end));
+
+        let mut end = 0;
+
+        for range in ranges {
+            if range.start < end || range.start > range.end || range.end > self.end {
+                return Err(SnapshotError::Corrupted);
+            }
+
+            end = range.end;
+        }
+
+        let allocated = self.used_ranges.values().map(Range::len).sum::<usize>();
+
+        if allocated != self.allocated_count * self.item_size {
+            return Err(SnapshotError::Corrupted);
+        }
+
+        let mut listed = SecondaryMap::<AddressId, ()>::new();
+
+        for (id, group) in &self.groups {
+            if group.len != group.members.len() {
+                return Err(SnapshotError::Corrupted);
+            }
+
+            for &member in &group.members {
+                if !self.used_ranges.contains_key(member)
+                    || self.group_of.get(member) != Some(&id)
+                    || listed.insert(member, ()).is_some()
+                {
+                    return Err(SnapshotError::Corrupted);
+                }
+            }
+        }
+
+        // Every allocation in `group_of` is listed in its group
+        if listed.len() != self.group_of.len() {
+            return Err(SnapshotError::Corrupted);
+        }
+
+        if !self
+            .priorities
+            .keys()
+            .all(|index| self.used_ranges.contains_key(index))
+            || !self
+                .labels
+                .keys()
+                .all(|index| self.used_ranges.contains_key(index))
+        {
+            return Err(SnapshotError::Corrupted);
+        }
+
+        Ok(())
+    }
+}
+
+impl Snapshot {
+    /// Store the snapshot as a binary blob, which starts with a format
+    /// version so older snapshots can still be read by newer versions
+    #[cfg(feature = "serde")]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::from(MAGIC);
+        bytes.extend(FORMAT_VERSION.to_le_bytes());
+
+        bincode::serialize_into(&mut bytes, &self.state)
+            .expect("Serializing into a Vec can't fail");
+
+        bytes
+    }
+
+    /// Read a snapshot stored with `.to_bytes()`
+    #[cfg(feature = "serde")]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let header = MAGIC.len() + core::mem::size_of::<u32>();
+
+        if bytes.len() < header || bytes[..MAGIC.len()] != MAGIC {
+            return Err(SnapshotError::NotASnapshot);
+        }
+
+        let version = u32::from_le_bytes(bytes[MAGIC.len()..header].try_into().unwrap());
+
+        if version != FORMAT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let state = bincode::deserialize(&bytes[header..]).map_err(|_| SnapshotError::Corrupted)?;
+
+        Ok(Self { state })
+    }
+
+    /// The size of the memory in the snapshot in bytes
+    pub fn size(&self) -> usize {
+        self.state.data.len()
+    }
+}
+
+/// Why a `Snapshot` could not be read or restored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// The bytes don't start like a snapshot does
+    NotASnapshot,
+    /// The snapshot was stored in a format version that isn't supported
+    UnsupportedVersion(u32),
+    /// The snapshot is cut off or otherwise damaged, its allocations overlap
+    /// or don't fit in its memory, or it refers to allocations or groups that
+    /// aren't in it
+    Corrupted,
+    /// The snapshot was taken of memory with items of a different size
+    ItemSizeMismatch { expected: usize, found: usize },
+    /// The snapshot was taken of memory with a CPU mirror and is restored
+    /// into memory without one, or the other way around
+    MirrorMismatch,
+}
+
+impl core::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::NotASnapshot => write!(f, "Not a snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported snapshot format version {version}")
+            }
+            SnapshotError::Corrupted => write!(f, "Snapshot is corrupted"),
+            SnapshotError::ItemSizeMismatch { expected, found } => write!(
+                f,
+                "Snapshot has items of {found} bytes, expected {expected} bytes"
+            ),
+            SnapshotError::MirrorMismatch => {
+                write!(f, "Snapshot and memory don't both have a CPU mirror")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
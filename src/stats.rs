@@ -0,0 +1,91 @@
+use std::collections::BTreeMap;
+
+use crate::Label;
+
+/// A summary of the health of a `GpuMemory`, returned by
+/// `GpuMemory::stats()`. Every size is in bytes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    /// The amount of live allocations
+    pub allocations: usize,
+    /// The amount of separate ranges of free memory the allocator can hand
+    /// out without growing the buffer
+    pub free_ranges: usize,
+    /// The biggest allocation that fits without growing the buffer
+    pub largest_free_block: usize,
+    /// The total size of all free ranges
+    pub free_bytes: usize,
+    /// The fraction of free memory that is not part of the largest free
+    /// block, from 0 (all free memory is in one piece) to 1 (free memory is
+    /// split into many tiny pieces)
+    pub fragmentation: f32,
+    /// The size of the buffer on the GPU
+    pub gpu_capacity: u64,
+    /// The amount of memory reserved for the copy of the buffer on the CPU
+    pub cpu_capacity: usize,
+    /// The most memory that was allocated at once
+    pub peak_size: usize,
+    /// The total amount of memory written to the GPU so far
+    pub uploaded_bytes: u64,
+    /// The allocations made with `GpuMemory::allocate_labeled()`, by label
+    pub labels: BTreeMap<Label, LabelStats>,
+}
+
+/// The allocations with the same label in `Stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LabelStats {
+    pub allocations: usize,
+    pub size: usize,
+}
+
+impl Stats {
+    /// Gather the stats of an allocator, `free_ranges` are the sizes of its
+    /// free ranges
+    pub(crate) fn new(
+        allocations: usize,
+        free_ranges: impl IntoIterator<Item = usize>,
+        buffer: &wgpu::Buffer,
+        cpu_capacity: usize,
+        counters: &Counters,
+    ) -> Self {
+        let mut stats = Self {
+            allocations,
+            gpu_capacity: buffer.size(),
+            cpu_capacity,
+            peak_size: counters.peak_size,
+            uploaded_bytes: counters.uploaded_bytes,
+            ..Default::default()
+        };
+
+        for len in free_ranges.into_iter().filter(|len| *len > 0) {
+            stats.free_ranges += 1;
+            stats.free_bytes += len;
+            stats.largest_free_block = stats.largest_free_block.max(len);
+        }
+
+        if stats.free_bytes > 0 {
+            stats.fragmentation = 1.0 - stats.largest_free_block as f32 / stats.free_bytes as f32;
+        }
+
+        stats
+    }
+}
+
+/// The parts of `Stats` that can't be derived from the state of an allocator,
+/// and have to be kept track of along the way
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Counters {
+    pub(crate) peak_size: usize,
+    pub(crate) uploaded_bytes: u64,
+}
+
+impl Counters {
+    /// Remember `size` if it's the most memory allocated so far
+    pub(crate) fn record_size(&mut self, size: usize) {
+        self.peak_size = self.peak_size.max(size);
+    }
+
+    pub(crate) fn record_upload(&mut self, bytes: usize) {
+        self.uploaded_bytes += bytes as u64;
+    }
+}
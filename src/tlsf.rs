@@ -0,0 +1,620 @@
+use std::{marker::PhantomData, ops::Range};
+
+use humansize::{format_size, DECIMAL};
+use slotmap::SlotMap;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    recreate_buffer, split_all_mut, split_many_mut,
+    stats::{Counters, Stats},
+    upload_or_resize, AddressId, GetManyMutError, GpuMemory,
+};
+
+/// log2 of the amount of second level lists per first level list
+const SL_LOG2: u32 = 4;
+const SL_COUNT: usize = 1 << SL_LOG2;
+const FL_COUNT: usize = usize::BITS as usize - SL_LOG2 as usize + 1;
+
+slotmap::new_key_type! {
+    pub(crate) struct BlockId;
+}
+
+/// A physically contiguous section of the buffer, either allocated or free
+#[derive(Debug, Clone)]
+struct Block {
+    /// Offset in items
+    offset: usize,
+    /// Size in items
+    size: usize,
+    free: bool,
+    /// The block right before this one in the buffer
+    prev: Option<BlockId>,
+    /// The block right after this one in the buffer
+    next: Option<BlockId>,
+    /// Links in the segregated free list, only used while `free`
+    prev_free: Option<BlockId>,
+    next_free: Option<BlockId>,
+}
+
+#[derive(Debug, Clone)]
+struct Allocation {
+    block: BlockId,
+    len: usize,
+}
+
+/// The first and second level list a block of `size` items is stored in
+fn mapping_insert(size: usize) -> (usize, usize) {
+    if size < SL_COUNT {
+        (0, size)
+    } else {
+        let fl = size.ilog2();
+        let sl = (size >> (fl - SL_LOG2)) ^ SL_COUNT;
+
+        ((fl - SL_LOG2 + 1) as usize, sl)
+    }
+}
+
+/// The first list that only contains blocks of at least `size` items
+fn mapping_search(size: usize) -> (usize, usize) {
+    if size < SL_COUNT {
+        mapping_insert(size)
+    } else {
+        mapping_insert(size.saturating_add((1 << (size.ilog2() - SL_LOG2)) - 1))
+    }
+}
+
+/// The bookkeeping of a TLSF allocator, every offset and size is in items.
+/// This doesn't own any memory, so it can be used to manage any part of a
+/// buffer.
+#[derive(Debug)]
+pub(crate) struct Tlsf {
+    blocks: SlotMap<BlockId, Block>,
+    /// The last block in the buffer
+    last_block: Option<BlockId>,
+    free_lists: [[Option<BlockId>; SL_COUNT]; FL_COUNT],
+    fl_bitmap: usize,
+    sl_bitmaps: [u32; FL_COUNT],
+    capacity: usize,
+}
+
+/// A two-level segregated fit (TLSF) allocator. Free blocks are kept in lists
+/// by size class, and a pair of bitmaps tracks which of those lists aren't
+/// empty, so finding a block that fits and merging a freed block with its
+/// neighbours are both O(1). This makes allocating and freeing take a
+/// predictable amount of time no matter how fragmented the buffer is, at the
+/// cost of a bit more bookkeeping than `SimpleGpuMemory`. Allocations never
+/// move, so their offset in the buffer can be relied on until they are freed.
+#[derive(Debug)]
+pub struct TlsfGpuMemory<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> {
+    buffer: wgpu::Buffer,
+    data: Vec<u8>,
+    tlsf: Tlsf,
+    allocations: SlotMap<AddressId, Allocation>,
+    allocated_count: usize,
+    counters: Counters,
+
+    mutated: bool,
+    _phantom: PhantomData<T>,
+}
+
+impl Tlsf {
+    pub(crate) fn new() -> Self {
+        Self {
+            blocks: SlotMap::with_key(),
+            last_block: None,
+            free_lists: [[None; SL_COUNT]; FL_COUNT],
+            fl_bitmap: 0,
+            sl_bitmaps: [0; FL_COUNT],
+            capacity: 0,
+        }
+    }
+
+    /// The amount of items managed by the allocator
+    pub(crate) fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// The size in items of every free block
+    pub(crate) fn free_blocks(&self) -> impl Iterator<Item = usize> + '_ {
+        self.blocks
+            .values()
+            .filter(|block| block.free)
+            .map(|block| block.size)
+    }
+
+    /// Whether the block at `id` exists and is in use
+    pub(crate) fn is_allocated(&self, id: BlockId) -> bool {
+        self.blocks.get(id).is_some_and(|block| !block.free)
+    }
+
+    /// The offset of the block at `id` in items
+    pub(crate) fn offset(&self, id: BlockId) -> usize {
+        self.blocks[id].offset
+    }
+
+    fn insert_free_block(&mut self, id: BlockId) {
+        let (fl, sl) = mapping_insert(self.blocks[id].size);
+        let head = self.free_lists[fl][sl];
+
+        let block = &mut self.blocks[id];
+        block.free = true;
+        block.prev_free = None;
+        block.next_free = head;
+
+        if let Some(head) = head {
+            self.blocks[head].prev_free = Some(id);
+        }
+
+        self.free_lists[fl][sl] = Some(id);
+        self.fl_bitmap |= 1 << fl;
+        self.sl_bitmaps[fl] |= 1 << sl;
+    }
+
+    fn remove_free_block(&mut self, id: BlockId) {
+        let (fl, sl) = mapping_insert(self.blocks[id].size);
+
+        let block = &mut self.blocks[id];
+        let (prev_free, next_free) = (block.prev_free.take(), block.next_free.take());
+        block.free = false;
+
+        if let Some(next_free) = next_free {
+            self.blocks[next_free].prev_free = prev_free;
+        }
+
+        match prev_free {
+            Some(prev_free) => self.blocks[prev_free].next_free = next_free,
+            None => {
+                self.free_lists[fl][sl] = next_free;
+
+                if next_free.is_none() {
+                    self.sl_bitmaps[fl] &= !(1 << sl);
+
+                    if self.sl_bitmaps[fl] == 0 {
+                        self.fl_bitmap &= !(1 << fl);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Find a free block of at least `size` items using the bitmaps
+    fn find_free_block(&self, size: usize) -> Option<BlockId> {
+        let (mut fl, sl) = mapping_search(size);
+
+        if fl >= FL_COUNT {
+            return None;
+        }
+
+        let mut sl_bitmap = self.sl_bitmaps[fl] & (!0 << sl);
+
+        if sl_bitmap == 0 {
+            // Any block in a higher first level list is big enough
+            let fl_bitmap = self.fl_bitmap & usize::MAX.checked_shl(fl as u32 + 1).unwrap_or(0);
+
+            if fl_bitmap == 0 {
+                return None;
+            }
+
+            fl = fl_bitmap.trailing_zeros() as usize;
+            sl_bitmap = self.sl_bitmaps[fl];
+        }
+
+        self.free_lists[fl][sl_bitmap.trailing_zeros() as usize]
+    }
+
+    /// Split the block at `id` so it's exactly `size` items, the remainder
+    /// becomes a new free block
+    fn split_block(&mut self, id: BlockId, size: usize) {
+        let block = &self.blocks[id];
+
+        if block.size <= size {
+            return;
+        }
+
+        let remainder = self.blocks.insert(Block {
+            offset: block.offset + size,
+            size: block.size - size,
+            free: false,
+            prev: Some(id),
+            next: block.next,
+            prev_free: None,
+            next_free: None,
+        });
+
+        match self.blocks[id].next {
+            Some(next) => self.blocks[next].prev = Some(remainder),
+            None => self.last_block = Some(remainder),
+        }
+
+        self.blocks[id].size = size;
+        self.blocks[id].next = Some(remainder);
+
+        self.free(remainder);
+    }
+
+    /// Merge the block at `id` into the block before it, returning the
+    /// merged block
+    fn merge_with_prev(&mut self, id: BlockId) -> BlockId {
+        let block = self.blocks.remove(id).unwrap();
+        let prev = block.prev.unwrap();
+
+        self.blocks[prev].size += block.size;
+        self.blocks[prev].next = block.next;
+
+        match block.next {
+            Some(next) => self.blocks[next].prev = Some(prev),
+            None => self.last_block = Some(prev),
+        }
+
+        prev
+    }
+
+    /// Mark the block at `id` as free, merging it with its neighbours if they
+    /// are free too
+    pub(crate) fn free(&mut self, mut id: BlockId) {
+        if let Some(next) = self.blocks[id].next.filter(|next| self.blocks[*next].free) {
+            self.remove_free_block(next);
+            self.merge_with_prev(next);
+        }
+
+        if let Some(prev) = self.blocks[id].prev.filter(|prev| self.blocks[*prev].free) {
+            self.remove_free_block(prev);
+            id = self.merge_with_prev(id);
+        }
+
+        self.insert_free_block(id);
+    }
+
+    /// Add at least `size` items of free memory at the end of the buffer
+    fn grow(&mut self, size: usize) {
+        let offset = self.capacity;
+        let size = size.max(self.capacity);
+
+        let id = self.blocks.insert(Block {
+            offset,
+            size,
+            free: false,
+            prev: self.last_block,
+            next: None,
+            prev_free: None,
+            next_free: None,
+        });
+
+        if let Some(last_block) = self.last_block {
+            self.blocks[last_block].next = Some(id);
+        }
+
+        self.last_block = Some(id);
+        self.capacity += size;
+
+        self.free(id);
+    }
+
+    /// Allocate a block of `size` items, growing the capacity if no free
+    /// block fits
+    pub(crate) fn allocate(&mut self, size: usize) -> BlockId {
+        // Empty blocks can't be told apart from their neighbours
+        let size = size.max(1);
+
+        let id = match self.find_free_block(size) {
+            Some(id) => id,
+            None => {
+                self.grow(size);
+                self.find_free_block(size)
+                    .or(self.last_block)
+                    .expect("Grown TLSF allocator has no free block")
+            }
+        };
+
+        self.remove_free_block(id);
+        self.split_block(id, size);
+
+        id
+    }
+
+    /// Make the block at `id` exactly `size` items in place, returns `false`
+    /// if the block can't grow because the memory after it is in use
+    pub(crate) fn resize(&mut self, id: BlockId, size: usize) -> bool {
+        let size = size.max(1);
+        let block = &self.blocks[id];
+
+        if size > block.size {
+            let Some(next) = block.next.filter(|next| {
+                let next = &self.blocks[*next];
+
+                next.free && block.size + next.size >= size
+            }) else {
+                return false;
+            };
+
+            self.remove_free_block(next);
+            self.merge_with_prev(next);
+        }
+
+        self.split_block(id, size);
+
+        true
+    }
+
+    /// Drop the last block in the buffer if it's free
+    pub(crate) fn truncate(&mut self) {
+        let Some(last_block) = self.last_block.filter(|id| self.blocks[*id].free) else {
+            return;
+        };
+
+        self.remove_free_block(last_block);
+
+        let block = self.blocks.remove(last_block).unwrap();
+
+        if let Some(prev) = block.prev {
+            self.blocks[prev].next = None;
+        }
+
+        self.last_block = block.prev;
+        self.capacity = block.offset;
+    }
+}
+
+impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> TlsfGpuMemory<T> {
+    /// The offset in bytes of the allocated memory at `index` in the buffer,
+    /// this does not change until the memory is freed or resized
+    pub fn offset(&self, index: &AddressId) -> wgpu::BufferAddress {
+        self.byte_range(&self.allocations[*index]).start as wgpu::BufferAddress
+    }
+
+    /// The amount of items the buffer can hold before it has to grow
+    pub fn capacity(&self) -> usize {
+        self.tlsf.capacity()
+    }
+
+    fn byte_range(&self, allocation: &Allocation) -> Range<usize> {
+        let start = self.tlsf.offset(allocation.block) * core::mem::size_of::<T>();
+
+        start..(start + allocation.len * core::mem::size_of::<T>())
+    }
+
+    /// Make the CPU buffer fit the capacity of the allocator
+    fn fit_data(&mut self) {
+        let size = self.tlsf.capacity() * core::mem::size_of::<T>();
+
+        if self.data.len() != size {
+            log::trace!(
+                "Resizing TLSF allocator of size {} to {}",
+                format_size(self.data.len(), DECIMAL),
+                format_size(size, DECIMAL)
+            );
+
+            self.data.resize(size, 0);
+        }
+    }
+}
+
+/// - `Truncate`: shrink the buffer to cut off the unallocated memory at the
+///   end of it. Allocations never move, so this can only free memory at the
+///   end of the buffer.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum Strategy {
+    #[default]
+    Truncate,
+}
+
+impl core::fmt::Display for Strategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Strategy::Truncate => "Truncate",
+            }
+        )
+    }
+}
+
+impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern> GpuMemory<T> for TlsfGpuMemory<T> {
+    type Index = AddressId;
+    type OptimizationStrategy = Strategy;
+
+    fn new(usages: wgpu::BufferUsages, device: &wgpu::Device) -> Self {
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("wgpu_text TLSF Allocator"),
+            size: core::mem::size_of::<T>() as wgpu::BufferAddress,
+            usage: usages | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            buffer,
+            data: Vec::new(),
+            tlsf: Tlsf::new(),
+            allocations: SlotMap::new(),
+            allocated_count: 0,
+            counters: Counters::default(),
+            mutated: false,
+            _phantom: Default::default(),
+        }
+    }
+
+    fn mutated(&self) -> bool {
+        self.mutated
+    }
+
+    fn allocate(&mut self, count: usize) -> Self::Index {
+        self.mutated = true;
+
+        let block = self.tlsf.allocate(count);
+        self.fit_data();
+
+        self.allocated_count += count;
+        self.counters
+            .record_size(self.allocated_count * core::mem::size_of::<T>());
+
+        self.allocations.insert(Allocation { block, len: count })
+    }
+
+    fn get(&mut self, index: &Self::Index) -> &mut [T] {
+        self.mutated = true;
+
+        let range = self.byte_range(&self.allocations[*index]);
+
+        bytemuck::cast_slice_mut(&mut self.data[range])
+    }
+
+    fn get_ref(&self, index: &Self::Index) -> &[T] {
+        bytemuck::cast_slice(&self.data[self.byte_range(&self.allocations[*index])])
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Self::Index, &[T])> + '_ {
+        self.allocations.iter().map(|(index, allocation)| {
+            (
+                index,
+                bytemuck::cast_slice(&self.data[self.byte_range(allocation)]),
+            )
+        })
+    }
+
+    fn get_many_mut<const N: usize>(
+        &mut self,
+        indices: [&Self::Index; N],
+    ) -> Result<[&mut [T]; N], GetManyMutError> {
+        let ranges = indices.map(|index| self.byte_range(&self.allocations[*index]));
+        let memory = split_many_mut(&mut self.data, indices, &ranges)?;
+
+        self.mutated = true;
+
+        Ok(memory)
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = (Self::Index, &mut [T])> + '_ {
+        self.mutated = true;
+
+        let allocations = self
+            .allocations
+            .iter()
+            .map(|(index, allocation)| (index, self.byte_range(allocation)))
+            .collect::<Vec<_>>();
+
+        split_all_mut(&mut self.data, allocations).into_iter()
+    }
+
+    fn len(&self) -> usize {
+        self.allocated_count
+    }
+
+    fn len_of(&self, index: &Self::Index) -> usize {
+        self.allocations[*index].len
+    }
+
+    fn contains(&self, index: &Self::Index) -> bool {
+        self.allocations.contains_key(*index)
+    }
+
+    fn resize(&mut self, index: &mut Self::Index, len: usize) {
+        let allocation = self.allocations[*index].clone();
+
+        self.allocated_count = self.allocated_count - allocation.len + len;
+        self.allocations[*index].len = len;
+        self.counters
+            .record_size(self.allocated_count * core::mem::size_of::<T>());
+
+        if self.tlsf.resize(allocation.block, len) {
+            return;
+        }
+
+        self.mutated = true;
+
+        let new_block = self.tlsf.allocate(len);
+        self.fit_data();
+
+        let source = self.byte_range(&allocation);
+        let destination = self.tlsf.offset(new_block) * core::mem::size_of::<T>();
+        self.data.copy_within(source, destination);
+
+        self.tlsf.free(allocation.block);
+        self.allocations[*index].block = new_block;
+    }
+
+    fn free(&mut self, index: Self::Index) {
+        self.mutated = true;
+
+        if let Some(allocation) = self.allocations.remove(index) {
+            self.allocated_count -= allocation.len;
+
+            self.tlsf.free(allocation.block);
+        }
+    }
+
+    fn upload(&mut self, queue: &wgpu::Queue, device: &wgpu::Device) {
+        if !self.mutated {
+            return;
+        }
+
+        upload_or_resize(queue, device, &mut self.buffer, &self.data);
+        self.counters.record_upload(self.data.len());
+
+        self.mutated = false;
+    }
+
+    fn optimize(
+        &mut self,
+        strategy: Self::OptimizationStrategy,
+        _queue: &wgpu::Queue,
+        device: &wgpu::Device,
+    ) {
+        match strategy {
+            Strategy::Truncate => {
+                self.tlsf.truncate();
+                self.fit_data();
+                self.data.shrink_to_fit();
+
+                self.buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("wgpu_text Resized Buffer"),
+                    usage: self.buffer.usage() | wgpu::BufferUsages::COPY_DST,
+                    contents: &self.data,
+                });
+                self.counters.record_upload(self.data.len());
+
+                self.mutated = false;
+            }
+        }
+    }
+
+    fn recreate(&mut self, queue: &wgpu::Queue, device: &wgpu::Device) {
+        self.buffer = recreate_buffer(
+            queue,
+            device,
+            "wgpu_text TLSF Allocator",
+            self.buffer.usage(),
+            &self.data,
+            core::mem::size_of::<T>() as wgpu::BufferAddress,
+        );
+        self.counters.record_upload(self.data.len());
+
+        self.mutated = false;
+    }
+
+    fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    /// Allocations are spread out over the entire buffer, so this returns a
+    /// slice of all the memory managed by the allocator, use `.offset()` to
+    /// find where an allocation is stored in the buffer
+    fn buffer_slice(&self) -> wgpu::BufferSlice<'_> {
+        self.buffer.slice(..(self.data.len() as u64))
+    }
+
+    fn stats(&self) -> Stats {
+        let free_ranges = self
+            .tlsf
+            .free_blocks()
+            .map(|size| size * core::mem::size_of::<T>());
+
+        Stats::new(
+            self.allocations.len(),
+            free_ranges,
+            &self.buffer,
+            self.data.capacity(),
+            &self.counters,
+        )
+    }
+}
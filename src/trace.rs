@@ -0,0 +1,590 @@
+use std::{
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
+use humansize::{format_size, DECIMAL};
+use itertools::Itertools;
+
+use crate::{stats::Stats, GetManyMutError, GpuMemory, Label};
+
+/// Written at the start of every trace, followed by the format version
+const MAGIC: [u8; 4] = *b"WGMT";
+const FORMAT_VERSION: u32 = 1;
+
+/// A call made on a `GpuMemory`. Allocations are numbered in the order they
+/// were made, starting at 0, which is the `id` used by the other events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Allocate { count: usize },
+    Get { id: usize },
+    Resize { id: usize, len: usize },
+    Free { id: usize },
+    Upload,
+    Optimize,
+}
+
+impl Event {
+    fn tag(&self) -> u8 {
+        match self {
+            Event::Allocate { .. } => 0,
+            Event::Get { .. } => 1,
+            Event::Resize { .. } => 2,
+            Event::Free { .. } => 3,
+            Event::Upload => 4,
+            Event::Optimize => 5,
+        }
+    }
+}
+
+/// Every call made on a `GpuMemory` wrapped in a `Recording`, which can be
+/// stored with `.to_bytes()` and replayed against any `GpuMemory` with
+/// `.replay()`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Trace {
+    /// `size_of::<T>()` of the recorded memory
+    pub item_size: usize,
+    pub events: Vec<Event>,
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+
+    bytes.push(value as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Result<usize, TraceError> {
+    let mut value = 0usize;
+
+    for shift in (0..usize::BITS).step_by(7) {
+        let (&byte, rest) = bytes.split_first().ok_or(TraceError::Corrupted)?;
+        *bytes = rest;
+
+        value |= ((byte & 0x7f) as usize)
+            .checked_shl(shift)
+            .ok_or(TraceError::Corrupted)?;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(TraceError::Corrupted)
+}
+
+impl Trace {
+    /// Store the trace as a compact binary blob, which starts with a format
+    /// version so older traces can still be read by newer versions
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::from(MAGIC);
+        bytes.extend(FORMAT_VERSION.to_le_bytes());
+        write_varint(&mut bytes, self.item_size);
+
+        for event in &self.events {
+            bytes.push(event.tag());
+
+            match *event {
+                Event::Allocate { count } => write_varint(&mut bytes, count),
+                Event::Get { id } | Event::Free { id } => write_varint(&mut bytes, id),
+                Event::Resize { id, len } => {
+                    write_varint(&mut bytes, id);
+                    write_varint(&mut bytes, len);
+                }
+                Event::Upload | Event::Optimize => (),
+            }
+        }
+
+        bytes
+    }
+
+    /// Read a trace stored with `.to_bytes()`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TraceError> {
+        let header = MAGIC.len() + core::mem::size_of::<u32>();
+
+        if bytes.len() < header || bytes[..MAGIC.len()] != MAGIC {
+            return Err(TraceError::NotATrace);
+        }
+
+        let version = u32::from_le_bytes(bytes[MAGIC.len()..header].try_into().unwrap());
+
+        if version != FORMAT_VERSION {
+            return Err(TraceError::UnsupportedVersion(version));
+        }
+
+        let mut bytes = &bytes[header..];
+        let item_size = read_varint(&mut bytes)?;
+        let mut events = Vec::new();
+
+        while let Some((&tag, rest)) = bytes.split_first() {
+            bytes = rest;
+
+            events.push(match tag {
+                0 => Event::Allocate {
+                    count: read_varint(&mut bytes)?,
+                },
+                1 => Event::Get {
+                    id: read_varint(&mut bytes)?,
+                },
+                2 => Event::Resize {
+                    id: read_varint(&mut bytes)?,
+                    len: read_varint(&mut bytes)?,
+                },
+                3 => Event::Free {
+                    id: read_varint(&mut bytes)?,
+                },
+                4 => Event::Upload,
+                5 => Event::Optimize,
+                _ => return Err(TraceError::Corrupted),
+            });
+        }
+
+        Ok(Self { item_size, events })
+    }
+
+    /// Make the calls in this trace on a new `M`, using the default
+    /// optimization strategy of `M` for `Optimize` events. The items are
+    /// `u32`s, as many as fit the recorded item size rounded up, so the
+    /// amount of memory is close to the recorded one.
+    pub fn replay<M: GpuMemory<u32>>(
+        &self,
+        queue: &wgpu::Queue,
+        device: &wgpu::Device,
+    ) -> Result<ReplayReport, TraceError> {
+        let mut mem = M::new(wgpu::BufferUsages::empty(), device);
+
+        self.replay_into(&mut mem, queue, device)
+    }
+
+    /// Like `.replay()`, but makes the calls on `mem`, so it can be inspected
+    /// afterwards, for example with `SimpleGpuMemory::memory_map()`
+    pub fn replay_into<M: GpuMemory<u32>>(
+        &self,
+        mem: &mut M,
+        queue: &wgpu::Queue,
+        device: &wgpu::Device,
+    ) -> Result<ReplayReport, TraceError> {
+        let scale = self.item_size.div_ceil(core::mem::size_of::<u32>()).max(1);
+
+        let mut indices: Vec<Option<M::Index>> = Vec::new();
+        let mut report = ReplayReport {
+            events: self.events.len(),
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+
+        for event in &self.events {
+            let index = |indices: &mut Vec<Option<M::Index>>, id: usize| {
+                indices
+                    .get_mut(id)
+                    .and_then(Option::take)
+                    .ok_or(TraceError::InvalidId(id))
+            };
+
+            match *event {
+                Event::Allocate { count } => indices.push(Some(mem.allocate(count * scale))),
+                Event::Get { id } => {
+                    let i = index(&mut indices, id)?;
+                    mem.get(&i);
+                    indices[id] = Some(i);
+                }
+                Event::Resize { id, len } => {
+                    let mut i = index(&mut indices, id)?;
+                    mem.resize(&mut i, len * scale);
+                    indices[id] = Some(i);
+                }
+                Event::Free { id } => mem.free(index(&mut indices, id)?),
+                Event::Upload => {
+                    let upload_start = Instant::now();
+                    mem.upload(queue, device);
+                    report.upload_time += upload_start.elapsed();
+                }
+                Event::Optimize => {
+                    let optimize_start = Instant::now();
+                    mem.optimize(Default::default(), queue, device);
+                    report.optimize_time += optimize_start.elapsed();
+                }
+            }
+
+            report.peak_size = report.peak_size.max(mem.size());
+            report.peak_buffer_size = report.peak_buffer_size.max(mem.buffer().size() as usize);
+        }
+
+        report.total_time = start.elapsed();
+        report.final_size = mem.size();
+        report.final_buffer_size = mem.buffer().size() as usize;
+
+        Ok(report)
+    }
+}
+
+/// Why a `Trace` could not be read or replayed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceError {
+    /// The bytes don't start like a trace does
+    NotATrace,
+    /// The trace was stored in a format version that isn't supported
+    UnsupportedVersion(u32),
+    /// The trace is cut off or otherwise damaged
+    Corrupted,
+    /// An event uses an allocation that doesn't exist or was already freed
+    InvalidId(usize),
+}
+
+impl core::fmt::Display for TraceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TraceError::NotATrace => write!(f, "Not a trace"),
+            TraceError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported trace format version {version}")
+            }
+            TraceError::Corrupted => write!(f, "Trace is corrupted"),
+            TraceError::InvalidId(id) => write!(f, "Trace uses invalid allocation {id}"),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+/// How a `GpuMemory` did while replaying a `Trace`
+#[derive(Debug, Clone, Default)]
+pub struct ReplayReport {
+    pub events: usize,
+    /// The most memory that was allocated at once
+    pub peak_size: usize,
+    /// The biggest the buffer on the GPU got
+    pub peak_buffer_size: usize,
+    pub final_size: usize,
+    pub final_buffer_size: usize,
+    pub total_time: Duration,
+    pub upload_time: Duration,
+    pub optimize_time: Duration,
+}
+
+impl ReplayReport {
+    /// The fraction of the buffer on the GPU that's not allocated at the end
+    /// of the trace, from 0 (all of it is allocated) to 1 (nothing is)
+    pub fn fragmentation(&self) -> f32 {
+        if self.final_buffer_size == 0 {
+            return 0.0;
+        }
+
+        1.0 - self.final_size as f32 / self.final_buffer_size as f32
+    }
+}
+
+impl core::fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "events:           {}", self.events)?;
+        writeln!(
+            f,
+            "peak size:        {}",
+            format_size(self.peak_size, DECIMAL)
+        )?;
+        writeln!(
+            f,
+            "peak buffer size: {}",
+            format_size(self.peak_buffer_size, DECIMAL)
+        )?;
+        writeln!(
+            f,
+            "final size:       {} in a buffer of {}",
+            format_size(self.final_size, DECIMAL),
+            format_size(self.final_buffer_size, DECIMAL)
+        )?;
+        writeln!(f, "fragmentation:    {:.1}%", self.fragmentation() * 100.0)?;
+        writeln!(f, "total time:       {:?}", self.total_time)?;
+        writeln!(f, "upload time:      {:?}", self.upload_time)?;
+        write!(f, "optimize time:    {:?}", self.optimize_time)
+    }
+}
+
+/// An index into a `Recording`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RecordedIndex<I> {
+    inner: I,
+    id: usize,
+}
+
+impl<I> RecordedIndex<I> {
+    /// The index into the wrapped `GpuMemory`
+    pub fn inner(&self) -> &I {
+        &self.inner
+    }
+}
+
+/// A wrapper around another `GpuMemory` that records every call made on it
+/// into a `Trace`, to reproduce performance problems or compare allocators
+/// later with `Trace::replay()` or the `wgpu_memory-replay` tool.
+#[derive(Debug)]
+pub struct Recording<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern, M: GpuMemory<T>> {
+    inner: M,
+    trace: Trace,
+    /// The inner index of every allocation by id, `None` once it's freed
+    allocations: Vec<Option<M::Index>>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern, M: GpuMemory<T>> Recording<T, M> {
+    /// The calls recorded so far
+    pub fn trace(&self) -> &Trace {
+        &self.trace
+    }
+
+    /// Take the calls recorded so far, recording continues into a new trace.
+    /// Allocations made before this can't be used in the new trace, so this
+    /// is best used at the end of a recording.
+    pub fn take_trace(&mut self) -> Trace {
+        Trace {
+            item_size: self.trace.item_size,
+            events: core::mem::take(&mut self.trace.events),
+        }
+    }
+
+    /// The wrapped `GpuMemory`, calls made on it directly aren't recorded
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    /// The wrapped `GpuMemory`, calls made on it directly aren't recorded
+    pub fn inner_mut(&mut self) -> &mut M {
+        &mut self.inner
+    }
+
+    fn add_allocation(&mut self, inner: M::Index) -> RecordedIndex<M::Index> {
+        let id = self.allocations.len();
+        self.allocations.push(Some(inner.clone()));
+
+        RecordedIndex { inner, id }
+    }
+}
+
+impl<T: Copy + bytemuck::NoUninit + bytemuck::AnyBitPattern, M: GpuMemory<T>> GpuMemory<T>
+    for Recording<T, M>
+{
+    type Index = RecordedIndex<M::Index>;
+    /// The inner `OptimizationStrategy`
+    type OptimizationStrategy = M::OptimizationStrategy;
+
+    fn new(usages: wgpu::BufferUsages, device: &wgpu::Device) -> Self {
+        Self {
+            inner: M::new(usages, device),
+            trace: Trace {
+                item_size: core::mem::size_of::<T>(),
+                events: Vec::new(),
+            },
+            allocations: Vec::new(),
+            _phantom: Default::default(),
+        }
+    }
+
+    fn mutated(&self) -> bool {
+        self.inner.mutated()
+    }
+
+    fn allocate(&mut self, count: usize) -> Self::Index {
+        self.trace.events.push(Event::Allocate { count });
+
+        let inner = self.inner.allocate(count);
+
+        self.add_allocation(inner)
+    }
+
+    /// Labels aren't recorded, the allocation is recorded like any other
+    fn allocate_labeled(&mut self, count: usize, label: impl Into<Label>) -> Self::Index {
+        self.trace.events.push(Event::Allocate { count });
+
+        let inner = self.inner.allocate_labeled(count, label);
+
+        self.add_allocation(inner)
+    }
+
+    fn label_of(&self, index: &Self::Index) -> Option<Label> {
+        self.inner.label_of(&index.inner)
+    }
+
+    fn get(&mut self, index: &Self::Index) -> &mut [T] {
+        self.trace.events.push(Event::Get { id: index.id });
+
+        self.inner.get(&index.inner)
+    }
+
+    /// Reads aren't recorded, as they don't change anything
+    fn get_ref(&self, index: &Self::Index) -> &[T] {
+        self.inner.get_ref(&index.inner)
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (Self::Index, &[T])> + '_ {
+        self.allocations
+            .iter()
+            .enumerate()
+            .filter_map(|(id, inner)| {
+                let inner = inner.as_ref()?;
+
+                Some((
+                    RecordedIndex {
+                        inner: inner.clone(),
+                        id,
+                    },
+                    self.inner.get_ref(inner),
+                ))
+            })
+    }
+
+    fn get_many_mut<const N: usize>(
+        &mut self,
+        indices: [&Self::Index; N],
+    ) -> Result<[&mut [T]; N], GetManyMutError> {
+        let memory = self.inner.get_many_mut(indices.map(|index| &index.inner))?;
+
+        self.trace
+            .events
+            .extend(indices.iter().map(|index| Event::Get { id: index.id }));
+
+        Ok(memory)
+    }
+
+    /// Recorded as a `.get()` of every allocation
+    fn iter_mut(&mut self) -> impl Iterator<Item = (Self::Index, &mut [T])> + '_ {
+        self.trace.events.extend(
+            self.allocations
+                .iter()
+                .enumerate()
+                .filter(|(_, inner)| inner.is_some())
+                .map(|(id, _)| Event::Get { id }),
+        );
+
+        // The inner indices can't be compared, so the ids are matched to the
+        // memory of the inner allocations by where it is. Only empty
+        // allocations can be in the same place, and those are interchangeable.
+        let inner = &mut self.inner;
+        let ids = self
+            .allocations
+            .iter()
+            .enumerate()
+            .filter_map(|(id, index)| {
+                let memory = inner.get(index.as_ref()?);
+
+                Some(((memory.as_ptr() as usize, memory.len()), id))
+            })
+            .sorted_unstable_by_key(|(place, _)| *place)
+            .collect::<Vec<_>>();
+
+        self.inner
+            .iter_mut()
+            .sorted_unstable_by_key(|(_, memory)| (memory.as_ptr() as usize, memory.len()))
+            .zip(ids)
+            .map(|((inner, memory), (_, id))| (RecordedIndex { inner, id }, memory))
+    }
+
+    /// Recorded as a `.get()` of the allocation, the same as `.fill()` and
+    /// `.set()`
+    fn write(&mut self, index: &Self::Index, offset: usize, data: &[T]) {
+        self.trace.events.push(Event::Get { id: index.id });
+
+        self.inner.write(&index.inner, offset, data);
+    }
+
+    fn fill(&mut self, index: &Self::Index, value: T) {
+        self.trace.events.push(Event::Get { id: index.id });
+
+        self.inner.fill(&index.inner, value);
+    }
+
+    fn set(&mut self, index: &Self::Index, i: usize, value: T) {
+        self.trace.events.push(Event::Get { id: index.id });
+
+        self.inner.set(&index.inner, i, value);
+    }
+
+    /// Recorded as a `.get()` of `destination`
+    fn copy_allocation(&mut self, source: &Self::Index, destination: &Self::Index) {
+        self.trace.events.push(Event::Get { id: destination.id });
+
+        self.inner
+            .copy_allocation(&source.inner, &destination.inner);
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn len_of(&self, index: &Self::Index) -> usize {
+        self.inner.len_of(&index.inner)
+    }
+
+    fn contains(&self, index: &Self::Index) -> bool {
+        self.inner.contains(&index.inner)
+    }
+
+    fn resize(&mut self, index: &mut Self::Index, len: usize) {
+        self.trace.events.push(Event::Resize { id: index.id, len });
+
+        self.inner.resize(&mut index.inner, len);
+        self.allocations[index.id] = Some(index.inner.clone());
+    }
+
+    fn free(&mut self, index: Self::Index) {
+        self.trace.events.push(Event::Free { id: index.id });
+
+        self.allocations[index.id] = None;
+        self.inner.free(index.inner)
+    }
+
+    fn upload(&mut self, queue: &wgpu::Queue, device: &wgpu::Device) {
+        self.trace.events.push(Event::Upload);
+
+        self.inner.upload(queue, device)
+    }
+
+    fn optimize(
+        &mut self,
+        strategy: Self::OptimizationStrategy,
+        queue: &wgpu::Queue,
+        device: &wgpu::Device,
+    ) {
+        self.trace.events.push(Event::Optimize);
+
+        self.inner.optimize(strategy, queue, device)
+    }
+
+    fn recreate(&mut self, queue: &wgpu::Queue, device: &wgpu::Device) {
+        self.inner.recreate(queue, device)
+    }
+
+    fn write_buffer_with(
+        &mut self,
+        index: &Self::Index,
+        queue: &wgpu::Queue,
+        device: &wgpu::Device,
+        write: impl FnOnce(&mut [T]),
+    ) {
+        self.trace.events.push(Event::Get { id: index.id });
+
+        self.inner
+            .write_buffer_with(&index.inner, queue, device, write)
+    }
+
+    fn buffer(&self) -> &wgpu::Buffer {
+        self.inner.buffer()
+    }
+
+    fn buffer_slice(&self) -> wgpu::BufferSlice<'_> {
+        self.inner.buffer_slice()
+    }
+
+    fn stats(&self) -> Stats {
+        self.inner.stats()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    fn size(&self) -> usize {
+        self.inner.size()
+    }
+}
@@ -0,0 +1,36 @@
+use std::ops::Range;
+
+use crate::AddressId;
+
+/// Why `SimpleGpuMemory::verify()` failed
+#[derive(Debug, Clone)]
+pub enum VerifyError {
+    /// The allocation at `index` differs between the GPU and the CPU in
+    /// `range`, in bytes from the start of the buffer
+    Mismatch {
+        index: AddressId,
+        range: Range<usize>,
+    },
+    /// The buffer could not be read back from the GPU
+    Readback(wgpu::BufferAsyncError),
+}
+
+impl core::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::Mismatch { index, range } => write!(
+                f,
+                "GPU buffer differs from the CPU mirror for allocation {index:?} at bytes {range:?}"
+            ),
+            VerifyError::Readback(err) => write!(f, "Could not read back the GPU buffer: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl From<wgpu::BufferAsyncError> for VerifyError {
+    fn from(err: wgpu::BufferAsyncError) -> Self {
+        VerifyError::Readback(err)
+    }
+}
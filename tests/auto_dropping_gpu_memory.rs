@@ -1,7 +1,9 @@
 use std::mem::size_of;
 
-use common::{get_wgpu, Entity};
-use wgpu_memory::{auto_drop::AutoDropping, simple::SimpleGpuMemory, GpuMemory};
+use common::{get_wgpu, read_buffer, Entity};
+use wgpu_memory::{
+    auto_drop::AutoDropping, ring::RingGpuMemory, simple::SimpleGpuMemory, GpuMemory,
+};
 
 mod common;
 
@@ -79,3 +81,92 @@ fn free_works() {
 
     assert_eq!(mem.size(), 0);
 }
+
+#[test]
+fn recreate_keeps_indices() {
+    let wgpu = get_wgpu();
+
+    let mut mem = AutoDropping::<Entity, SimpleGpuMemory<Entity>>::new(
+        wgpu::BufferUsages::VERTEX,
+        &wgpu.device,
+    );
+
+    let indices = (0..10)
+        .map(|i| {
+            let index = mem.allocate(i + 1);
+            mem.get(&index).fill(Entity { param: i as u32 });
+            index
+        })
+        .collect::<Vec<_>>();
+
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    // As if the first device was lost
+    let new_wgpu = get_wgpu();
+    mem.recreate(&new_wgpu.queue, &new_wgpu.device);
+
+    assert!(!mem.mutated());
+    assert!(mem.buffer().usage().contains(wgpu::BufferUsages::VERTEX));
+
+    let gpu_data = read_buffer(&new_wgpu, mem.buffer(), mem.buffer().size());
+    let gpu_entities: &[Entity] = bytemuck::cast_slice(&gpu_data);
+
+    // Nothing was freed, so the allocations are in order
+    let mut offset = 0;
+
+    for (i, index) in indices.iter().enumerate() {
+        assert!(mem.get(index).iter().all(|entity| entity.param == i as u32));
+        assert!(gpu_entities[offset..(offset + i + 1)]
+            .iter()
+            .all(|entity| entity.param == i as u32));
+
+        offset += i + 1;
+    }
+}
+
+#[test]
+fn read_works() {
+    let wgpu = get_wgpu();
+
+    let mut mem = AutoDropping::<Entity, SimpleGpuMemory<Entity>>::new(
+        wgpu::BufferUsages::empty(),
+        &wgpu.device,
+    );
+
+    let a = mem.allocate(2);
+    let b = mem.allocate(1);
+    mem.get(&a).fill(Entity { param: 1 });
+    mem.get(&b)[0] = Entity { param: 2 };
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    assert_eq!(mem.read(&a).len(), 2);
+    assert_eq!(mem.read(&b)[0].param, 2);
+    assert_eq!(mem.get_ref(&a)[1].param, 1);
+
+    // Indices from `.iter()` don't free their allocation when dropped
+    let allocations = mem.iter().map(|(index, _)| index).collect::<Vec<_>>();
+    assert_eq!(allocations.len(), 2);
+    drop(allocations);
+
+    assert_eq!(mem.size(), 3 * size_of::<Entity>());
+    assert!(!mem.mutated());
+}
+
+#[test]
+fn buffer_slice_is_forwarded() {
+    let wgpu = get_wgpu();
+
+    let mut mem = AutoDropping::<Entity, RingGpuMemory<Entity>>::new(
+        wgpu::BufferUsages::empty(),
+        &wgpu.device,
+    );
+
+    let _index = mem.allocate(3);
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    // Live allocations can be anywhere in the ring, so it hands out all of it
+    assert_eq!(
+        format!("{:?}", mem.buffer_slice()),
+        format!("{:?}", mem.buffer().slice(..))
+    );
+}
@@ -0,0 +1,157 @@
+use std::mem::size_of;
+
+use common::{get_wgpu, read_buffer, Entity};
+use wgpu_memory::{buddy::BuddyGpuMemory, GpuMemory};
+
+mod common;
+
+#[test]
+fn allocations_work() {
+    let wgpu = get_wgpu();
+
+    let mut mem = BuddyGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    for _ in 0..100 {
+        let mut index = mem.allocate(3);
+        assert_eq!(mem.size(), size_of::<Entity>() * 3);
+
+        mem.resize(&mut index, 10);
+        assert_eq!(mem.size(), size_of::<Entity>() * 10);
+
+        mem.resize(&mut index, 1);
+        assert_eq!(mem.size(), size_of::<Entity>());
+
+        mem.get(&index)[0] = Entity { param: 1 };
+        mem.free(index);
+    }
+
+    assert_eq!(mem.size(), 0);
+    assert_eq!(mem.capacity(), 32);
+}
+
+#[test]
+fn offsets_stay_stable() {
+    let wgpu = get_wgpu();
+
+    let mut mem = BuddyGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let indices = (0..64)
+        .map(|i| {
+            let index = mem.allocate(i % 5 + 1);
+            mem.get(&index).fill(Entity { param: i as u32 });
+            (index, mem.offset(&index))
+        })
+        .collect::<Vec<_>>();
+
+    for (index, _) in indices.iter().step_by(2) {
+        mem.free(*index);
+    }
+
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    for (i, (index, offset)) in indices.iter().enumerate().skip(1).step_by(2) {
+        assert_eq!(mem.offset(index), *offset);
+        assert!(mem.get(index).iter().all(|entity| entity.param == i as u32));
+    }
+}
+
+#[test]
+fn buddies_merge() {
+    let wgpu = get_wgpu();
+
+    let mut mem = BuddyGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let indices = (0..16).map(|_| mem.allocate(1)).collect::<Vec<_>>();
+    assert_eq!(mem.capacity(), 16);
+
+    for index in indices {
+        mem.free(index);
+    }
+
+    // All blocks merged back into one block that fits the entire buffer
+    let index = mem.allocate(16);
+    assert_eq!(mem.capacity(), 16);
+    assert_eq!(mem.offset(&index), 0);
+
+    mem.free(index);
+    mem.optimize(Default::default(), &wgpu.queue, &wgpu.device);
+
+    assert_eq!(mem.capacity(), 0);
+}
+
+#[test]
+fn recreate_keeps_data() {
+    let wgpu = get_wgpu();
+
+    let mut mem = BuddyGpuMemory::new(wgpu::BufferUsages::COPY_SRC, &wgpu.device);
+
+    let indices = (0..10)
+        .map(|i| {
+            let index = mem.allocate(i + 1);
+            mem.get(&index).fill(Entity { param: i as u32 });
+            index
+        })
+        .collect::<Vec<_>>();
+
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    let new_wgpu = get_wgpu();
+    mem.recreate(&new_wgpu.queue, &new_wgpu.device);
+
+    let gpu_data = read_buffer(&new_wgpu, mem.buffer(), mem.buffer().size());
+    let gpu_entities: &[Entity] = bytemuck::cast_slice(&gpu_data);
+
+    for (i, index) in indices.iter().enumerate() {
+        let offset = mem.offset(index) as usize / size_of::<Entity>();
+
+        assert!(gpu_entities[offset..(offset + i + 1)]
+            .iter()
+            .all(|entity| entity.param == i as u32));
+    }
+}
+
+#[test]
+fn get_many_mut_works() {
+    let wgpu = get_wgpu();
+
+    let mut mem = BuddyGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let a = mem.allocate(5);
+    let b = mem.allocate(2);
+
+    let [a_memory, b_memory] = mem.get_many_mut([&a, &b]).unwrap();
+    a_memory.fill(Entity { param: 3 });
+    b_memory.copy_from_slice(&a_memory[..2]);
+
+    assert!(mem.get_many_mut([&b, &b]).is_err());
+
+    let total = mem
+        .iter_mut()
+        .flat_map(|(_, memory)| memory.iter())
+        .map(|entity| entity.param)
+        .sum::<u32>();
+    assert_eq!(total, 3 * 7);
+}
+
+#[test]
+fn write_helpers_work() {
+    let wgpu = get_wgpu();
+
+    let mut mem = BuddyGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let a = mem.allocate(4);
+    let b = mem.allocate(4);
+
+    mem.fill(&a, Entity { param: 1 });
+    mem.set(&a, 3, Entity { param: 4 });
+    mem.write(&a, 1, &[Entity { param: 2 }, Entity { param: 3 }]);
+    mem.copy_allocation(&a, &b);
+
+    let params = mem
+        .get_ref(&b)
+        .iter()
+        .map(|entity| entity.param)
+        .collect::<Vec<_>>();
+    assert_eq!(params, [1, 2, 3, 4]);
+    assert!(mem.mutated());
+}
@@ -4,6 +4,7 @@ pub struct Entity {
     pub param: u32,
 }
 
+#[allow(dead_code)]
 pub struct Wgpu {
     pub instance: wgpu::Instance,
     pub adapter: wgpu::Adapter,
@@ -37,3 +38,26 @@ pub fn get_wgpu() -> Wgpu {
         queue,
     }
 }
+
+/// Copy the first `size` bytes of `buffer` back to the CPU
+#[allow(dead_code)]
+pub fn read_buffer(wgpu: &Wgpu, buffer: &wgpu::Buffer, size: u64) -> Vec<u8> {
+    let staging = wgpu.device.create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = wgpu.device.create_command_encoder(&Default::default());
+    encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, size);
+    wgpu.queue.submit([encoder.finish()]);
+
+    staging
+        .slice(..)
+        .map_async(wgpu::MapMode::Read, |result| result.unwrap());
+    wgpu.device.poll(wgpu::Maintain::Wait);
+
+    let data = staging.slice(..).get_mapped_range().to_vec();
+    data
+}
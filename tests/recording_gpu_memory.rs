@@ -0,0 +1,200 @@
+use std::mem::size_of;
+
+use common::{get_wgpu, Entity};
+use wgpu_memory::{
+    simple::SimpleGpuMemory,
+    tlsf::TlsfGpuMemory,
+    trace::{Event, Recording, Trace, TraceError},
+    GpuMemory,
+};
+
+mod common;
+
+#[test]
+fn recording_works() {
+    let wgpu = get_wgpu();
+
+    let mut mem = Recording::<Entity, SimpleGpuMemory<Entity>>::new(
+        wgpu::BufferUsages::empty(),
+        &wgpu.device,
+    );
+
+    let mut a = mem.allocate(4);
+    let b = mem.allocate(300);
+    mem.resize(&mut a, 8);
+    mem.get(&a)[0] = Entity { param: 1 };
+    mem.upload(&wgpu.queue, &wgpu.device);
+    mem.free(b);
+    mem.optimize(Default::default(), &wgpu.queue, &wgpu.device);
+
+    assert_eq!(mem.get(&a)[0].param, 1);
+    assert_eq!(mem.size(), size_of::<Entity>() * 8);
+
+    // Reads aren't recorded
+    let allocations = mem.iter().collect::<Vec<_>>();
+    assert_eq!(allocations.len(), 1);
+    assert_eq!(allocations[0].0.inner(), a.inner());
+    assert_eq!(allocations[0].1[0].param, 1);
+
+    let trace = mem.take_trace();
+
+    assert_eq!(trace.item_size, size_of::<Entity>());
+    assert_eq!(
+        trace.events,
+        [
+            Event::Allocate { count: 4 },
+            Event::Allocate { count: 300 },
+            Event::Resize { id: 0, len: 8 },
+            Event::Get { id: 0 },
+            Event::Upload,
+            Event::Free { id: 1 },
+            Event::Optimize,
+            Event::Get { id: 0 },
+        ]
+    );
+    assert!(mem.trace().events.is_empty());
+}
+
+#[test]
+fn trace_round_trip_works() {
+    let trace = Trace {
+        item_size: 12,
+        events: vec![
+            Event::Allocate { count: 1 },
+            Event::Allocate { count: 100_000 },
+            Event::Resize { id: 1, len: 300 },
+            Event::Get { id: 0 },
+            Event::Upload,
+            Event::Free { id: 0 },
+            Event::Optimize,
+        ],
+    };
+
+    let bytes = trace.to_bytes();
+    assert_eq!(Trace::from_bytes(&bytes).unwrap(), trace);
+
+    assert_eq!(Trace::from_bytes(b"nope"), Err(TraceError::NotATrace));
+    assert_eq!(
+        Trace::from_bytes(&bytes[..bytes.len() - 2]),
+        Err(TraceError::Corrupted)
+    );
+
+    let mut newer = bytes.clone();
+    newer[4] = 2;
+    assert_eq!(
+        Trace::from_bytes(&newer),
+        Err(TraceError::UnsupportedVersion(2))
+    );
+}
+
+#[test]
+fn replay_works() {
+    let wgpu = get_wgpu();
+
+    let mut mem = Recording::<Entity, SimpleGpuMemory<Entity>>::new(
+        wgpu::BufferUsages::empty(),
+        &wgpu.device,
+    );
+
+    let indices: Vec<_> = (1..50).map(|i| mem.allocate(i)).collect();
+    mem.upload(&wgpu.queue, &wgpu.device);
+    let peak_size = mem.size();
+
+    for index in indices.into_iter().step_by(2) {
+        mem.free(index);
+    }
+
+    mem.optimize(Default::default(), &wgpu.queue, &wgpu.device);
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    let trace = Trace::from_bytes(&mem.trace().to_bytes()).unwrap();
+
+    let simple = trace
+        .replay::<SimpleGpuMemory<u32>>(&wgpu.queue, &wgpu.device)
+        .unwrap();
+    assert_eq!(simple.events, trace.events.len());
+    assert_eq!(simple.peak_size, peak_size);
+    assert_eq!(simple.final_size, mem.size());
+
+    let tlsf = trace
+        .replay::<TlsfGpuMemory<u32>>(&wgpu.queue, &wgpu.device)
+        .unwrap();
+    assert_eq!(tlsf.peak_size, peak_size);
+    assert_eq!(tlsf.final_size, mem.size());
+    assert!(tlsf.peak_buffer_size >= peak_size);
+
+    let invalid = Trace {
+        item_size: 4,
+        events: vec![Event::Allocate { count: 1 }, Event::Free { id: 1 }],
+    };
+    assert_eq!(
+        invalid
+            .replay::<SimpleGpuMemory<u32>>(&wgpu.queue, &wgpu.device)
+            .unwrap_err(),
+        TraceError::InvalidId(1)
+    );
+}
+
+#[test]
+fn iter_mut_is_recorded() {
+    let wgpu = get_wgpu();
+
+    let mut mem =
+        Recording::<Entity, TlsfGpuMemory<Entity>>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let a = mem.allocate(2);
+    let b = mem.allocate(1);
+    let c = mem.allocate(3);
+    mem.free(b);
+
+    for (index, memory) in mem.iter_mut() {
+        assert!(index == a || index == c);
+        let param = if index == a { 2 } else { 3 };
+        memory.fill(Entity { param });
+    }
+
+    // Every index comes with its own memory
+    assert!(mem.get_ref(&a).iter().all(|entity| entity.param == 2));
+    assert!(mem.get_ref(&c).iter().all(|entity| entity.param == 3));
+
+    mem.get_many_mut([&c, &a]).unwrap();
+
+    assert_eq!(
+        &mem.trace().events[4..],
+        [
+            Event::Get { id: 0 },
+            Event::Get { id: 2 },
+            Event::Get { id: 2 },
+            Event::Get { id: 0 },
+        ]
+    );
+}
+
+#[test]
+fn write_helpers_are_recorded() {
+    let wgpu = get_wgpu();
+
+    let mut mem = Recording::<Entity, SimpleGpuMemory<Entity>>::new(
+        wgpu::BufferUsages::empty(),
+        &wgpu.device,
+    );
+
+    let a = mem.allocate(2);
+    let b = mem.allocate(2);
+
+    mem.fill(&a, Entity { param: 1 });
+    mem.set(&a, 1, Entity { param: 2 });
+    mem.write(&b, 0, &[Entity { param: 3 }]);
+    mem.copy_allocation(&a, &b);
+
+    assert_eq!(mem.get_ref(&b)[1].param, 2);
+    assert_eq!(
+        &mem.trace().events[2..],
+        [
+            Event::Get { id: 0 },
+            Event::Get { id: 0 },
+            Event::Get { id: 1 },
+            Event::Get { id: 1 },
+        ]
+    );
+}
@@ -0,0 +1,104 @@
+use std::mem::size_of;
+
+use common::{get_wgpu, read_buffer, Entity};
+use wgpu_memory::{ring::RingGpuMemory, GpuMemory};
+
+mod common;
+
+#[test]
+fn frames_release_allocations() {
+    let wgpu = get_wgpu();
+
+    let mut mem = RingGpuMemory::<Entity>::with_frames_in_flight(
+        wgpu::BufferUsages::empty(),
+        &wgpu.device,
+        2,
+    );
+
+    let index = mem.allocate(4);
+    assert_eq!(mem.size(), size_of::<Entity>() * 4);
+
+    mem.begin_frame();
+    mem.allocate(2);
+    assert_eq!(mem.size(), size_of::<Entity>() * 6);
+
+    // The allocations of the first frame are released now
+    mem.begin_frame();
+    assert_eq!(mem.size(), size_of::<Entity>() * 2);
+
+    mem.begin_frame();
+    assert_eq!(mem.size(), 0);
+
+    mem.free(index);
+    assert_eq!(mem.size(), 0);
+}
+
+#[test]
+fn ring_wraps_around() {
+    let wgpu = get_wgpu();
+
+    let mut mem = RingGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    for i in 0..100 {
+        mem.begin_frame();
+
+        let index = mem.allocate(3);
+        mem.get(&index).fill(Entity { param: i });
+
+        assert!(mem.get(&index).iter().all(|entity| entity.param == i));
+
+        mem.upload(&wgpu.queue, &wgpu.device);
+    }
+
+    // 2 frames in flight and 1 frame being written to, with some room to
+    // spare for skipping the end of the ring
+    assert!(mem.capacity() <= size_of::<Entity>() * 3 * 6);
+}
+
+#[test]
+fn growing_keeps_data() {
+    let wgpu = get_wgpu();
+
+    let mut mem = RingGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let indices = (0..100)
+        .map(|i| {
+            let index = mem.allocate(i + 1);
+            mem.get(&index).fill(Entity { param: i as u32 });
+            index
+        })
+        .collect::<Vec<_>>();
+
+    for (i, index) in indices.iter().enumerate() {
+        assert_eq!(mem.len_of(index), i + 1);
+        assert!(mem.get(index).iter().all(|entity| entity.param == i as u32));
+    }
+
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    assert!(mem.buffer().size() >= mem.capacity() as u64);
+}
+
+#[test]
+fn unaligned_items_work() {
+    let wgpu = get_wgpu();
+
+    // 6 bytes, so allocations don't start or end on
+    // `wgpu::COPY_BUFFER_ALIGNMENT`
+    let mut mem = RingGpuMemory::<[u16; 3]>::new(wgpu::BufferUsages::COPY_SRC, &wgpu.device);
+
+    for i in 0..50 {
+        mem.begin_frame();
+
+        let index = mem.allocate(i % 3 + 1);
+        mem.get(&index).fill([i as u16; 3]);
+
+        mem.upload(&wgpu.queue, &wgpu.device);
+
+        let gpu_data = read_buffer(&wgpu, mem.buffer(), mem.buffer().size());
+        let offset = mem.offset(&index) as usize;
+        let gpu_items: &[u16] = bytemuck::cast_slice(&gpu_data[offset..(offset + 6)]);
+
+        assert_eq!(gpu_items, [i as u16; 3]);
+    }
+}
@@ -1,7 +1,15 @@
-use std::mem::size_of;
+use std::{
+    mem::size_of,
+    sync::{Arc, Mutex},
+};
 
-use common::{get_wgpu, Entity};
-use wgpu_memory::{simple::SimpleGpuMemory, GpuMemory};
+use common::{get_wgpu, read_buffer, Entity};
+use wgpu_memory::{
+    leak::{LeakReport, OnLeak},
+    memory_map::Coloring,
+    simple::{SimpleGpuMemory, Strategy},
+    GetManyMutError, GpuMemory,
+};
 
 mod common;
 
@@ -71,3 +79,1316 @@ fn free_works() {
 
     assert_eq!(mem.size(), 0);
 }
+
+#[test]
+fn free_out_of_order_works() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let first = mem.allocate(1);
+    let second = mem.allocate(1);
+    let third = mem.allocate(1);
+
+    mem.get(&second)[0] = Entity { param: 2 };
+
+    mem.free(first);
+    mem.free(third);
+
+    // The freed memory on both sides of `second` must not be merged into one
+    let fourth = mem.allocate(2);
+    mem.get(&fourth).fill(Entity { param: 4 });
+
+    assert_eq!(mem.get(&second)[0].param, 2);
+
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    assert_eq!(mem.get(&second)[0].param, 2);
+    assert_eq!(mem.size(), size_of::<Entity>() * 3);
+}
+
+#[test]
+fn free_group_works() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let kept = mem.allocate(1);
+    let group = mem.create_group();
+
+    for i in 0..100 {
+        let mut index = mem.allocate_in(group, 1);
+
+        if i % 10 == 0 {
+            mem.resize(&mut index, 2);
+        }
+
+        mem.get(&index)[0] = Entity { param: i };
+    }
+
+    mem.get(&kept)[0] = Entity { param: 1000 };
+    assert_eq!(mem.group_len(group), 100);
+
+    mem.free_group(group);
+
+    assert_eq!(mem.group_len(group), 0);
+    assert_eq!(mem.size(), size_of::<Entity>());
+    assert_eq!(mem.get(&kept)[0].param, 1000);
+
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    assert_eq!(mem.get(&kept)[0].param, 1000);
+}
+
+#[test]
+fn freeing_group_members_works() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let kept = mem.allocate(1);
+    let group = mem.create_group();
+
+    let mut indices = (0..100)
+        .map(|_| mem.allocate_in(group, 1))
+        .collect::<Vec<_>>();
+
+    for (i, index) in indices.iter_mut().enumerate() {
+        mem.resize(index, 2);
+        mem.get(index)[0] = Entity { param: i as u32 };
+    }
+
+    for index in indices.drain(10..) {
+        mem.free(index);
+    }
+
+    assert_eq!(mem.group_len(group), 10);
+
+    let other = mem.allocate_in(group, 1);
+
+    assert_eq!(mem.group_len(group), 11);
+
+    for (i, index) in indices.iter().enumerate() {
+        assert_eq!(mem.get(index)[0].param, i as u32);
+    }
+
+    mem.get(&kept)[0] = Entity { param: 1000 };
+    mem.free_group(group);
+
+    assert!(!mem.contains(&other));
+    assert_eq!(mem.group_len(group), 0);
+    assert_eq!(mem.size(), size_of::<Entity>());
+    assert_eq!(mem.get(&kept)[0].param, 1000);
+}
+
+#[test]
+fn defragment_works() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device);
+    mem.set_compact_on_upload(false);
+
+    let indices = (0..10)
+        .map(|i| {
+            let index = mem.allocate(1);
+            mem.get(&index)[0] = Entity { param: i };
+            index
+        })
+        .collect::<Vec<_>>();
+
+    for index in indices.iter().step_by(2) {
+        mem.free(*index);
+    }
+
+    mem.upload(&wgpu.queue, &wgpu.device);
+    assert_eq!(mem.fragmentation(), 0.5);
+
+    let mut calls = 0;
+
+    while mem.fragmentation() > 0.0 {
+        assert!(mem.defragment(size_of::<Entity>()).len() <= 1);
+        calls += 1;
+    }
+
+    assert!(calls <= 5);
+
+    for (i, index) in indices.iter().enumerate().skip(1).step_by(2) {
+        assert_eq!(mem.get(index)[0].param, i as u32);
+    }
+
+    assert_eq!(mem.defragment(usize::MAX), Vec::new());
+    assert_eq!(mem.size(), size_of::<Entity>() * 5);
+}
+
+#[test]
+fn defragment_keeps_changes() {
+    let wgpu = get_wgpu();
+
+    for with_belt in [false, true] {
+        let mut mem = SimpleGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+        let indices = (0..4)
+            .map(|i| {
+                let index = mem.allocate(1);
+                mem.get(&index)[0] = Entity { param: i };
+                index
+            })
+            .collect::<Vec<_>>();
+
+        mem.upload(&wgpu.queue, &wgpu.device);
+        mem.set_compact_on_upload(false);
+
+        mem.free(indices[1]);
+        mem.free(indices[2]);
+        // Changed before moving, so it has to be uploaded where it ends up
+        mem.get(&indices[3])[0] = Entity { param: 7 };
+        assert_eq!(mem.defragment(100), [indices[3]]);
+
+        let mut belt = wgpu::util::StagingBelt::new(256);
+        let mut encoder = wgpu.device.create_command_encoder(&Default::default());
+
+        if with_belt {
+            mem.upload_with_encoder(&wgpu.queue, &wgpu.device, &mut encoder, &mut belt);
+        } else {
+            mem.compact_with_encoder(&wgpu.queue, &wgpu.device, &mut encoder);
+        }
+
+        belt.finish();
+        wgpu.queue.submit([encoder.finish()]);
+
+        let gpu_data = read_buffer(&wgpu, mem.buffer(), mem.buffer().size());
+        let gpu_entities: &[Entity] = bytemuck::cast_slice(&gpu_data);
+
+        assert_eq!(gpu_entities[0].param, 0);
+        assert_eq!(gpu_entities[1].param, 7);
+    }
+}
+
+#[test]
+fn gpu_compaction_works() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let indices = (0..16)
+        .map(|i| {
+            let index = mem.allocate(i % 3 + 1);
+            mem.get(&index).fill(Entity { param: i as u32 });
+            index
+        })
+        .collect::<Vec<_>>();
+
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    for index in indices.iter().step_by(3) {
+        mem.free(*index);
+    }
+
+    // Changed after the last upload, so this has to be uploaded before moving
+    mem.get(&indices[1])[0] = Entity { param: 100 };
+
+    let strategies = [
+        Strategy::SortSizeDescending,
+        Strategy::SortSizeAscending,
+        Strategy::Truncate,
+    ];
+
+    for strategy in strategies {
+        let mut encoder = wgpu.device.create_command_encoder(&Default::default());
+
+        match strategy {
+            Strategy::Truncate => mem.compact_with_encoder(&wgpu.queue, &wgpu.device, &mut encoder),
+            _ => mem.optimize_with_encoder(strategy, &wgpu.queue, &wgpu.device, &mut encoder),
+        }
+
+        wgpu.queue.submit([encoder.finish()]);
+        assert!(!mem.mutated());
+
+        let gpu_data = read_buffer(&wgpu, mem.buffer(), mem.size() as u64);
+        let gpu_entities: &[Entity] = bytemuck::cast_slice(&gpu_data);
+
+        for (i, index) in indices.iter().enumerate().filter(|(i, _)| i % 3 != 0) {
+            let offset = mem.offset(index) as usize / size_of::<Entity>();
+            let expected = if i == 1 { 100 } else { i as u32 };
+
+            assert_eq!(gpu_entities[offset].param, expected);
+            assert_eq!(mem.get(index)[0].param, expected);
+        }
+    }
+
+    let mut encoder = wgpu.device.create_command_encoder(&Default::default());
+    mem.optimize_with_encoder(Strategy::Truncate, &wgpu.queue, &wgpu.device, &mut encoder);
+    wgpu.queue.submit([encoder.finish()]);
+
+    assert_eq!(mem.buffer().size(), mem.size() as u64);
+}
+
+#[test]
+fn without_mirror_works() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::without_mirror(wgpu::BufferUsages::empty(), &wgpu.device);
+    assert!(!mem.has_mirror());
+
+    let mut allocations = (0..16)
+        .map(|i| {
+            let index = mem.allocate(i % 3 + 1);
+            mem.get(&index).fill(Entity { param: i as u32 });
+            (index, i as u32)
+        })
+        .collect::<Vec<_>>();
+
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    let check = |mem: &SimpleGpuMemory<Entity>, allocations: &[(_, u32)]| {
+        let gpu_data = read_buffer(&wgpu, mem.buffer(), mem.size() as u64);
+        let gpu_entities: &[Entity] = bytemuck::cast_slice(&gpu_data);
+
+        for (index, expected) in allocations {
+            let offset = mem.offset(index) as usize / size_of::<Entity>();
+            let len = mem.len_of(index);
+
+            assert!(gpu_entities[offset..(offset + len)]
+                .iter()
+                .all(|entity| entity.param == *expected));
+        }
+    };
+
+    for (i, (index, _)) in allocations.clone().into_iter().enumerate().rev() {
+        if i % 3 == 0 {
+            mem.free(index);
+            allocations.remove(i);
+        }
+    }
+
+    // The entire allocation has to be written, there's nothing to read from
+    mem.get(&allocations[0].0).fill(Entity { param: 100 });
+    allocations[0].1 = 100;
+
+    // Compacting and growing both happen on the GPU
+    let index = mem.allocate(64);
+    mem.get(&index).fill(Entity { param: 64 });
+    allocations.push((index, 64));
+
+    mem.upload(&wgpu.queue, &wgpu.device);
+    assert_eq!(mem.fragmentation(), 0.0);
+    check(&mem, &allocations);
+
+    mem.optimize(Strategy::SortSizeAscending, &wgpu.queue, &wgpu.device);
+    mem.upload(&wgpu.queue, &wgpu.device);
+    check(&mem, &allocations);
+
+    let (index, _) = allocations.remove(1);
+    mem.free(index);
+    mem.optimize(Strategy::Truncate, &wgpu.queue, &wgpu.device);
+    check(&mem, &allocations);
+
+    assert_eq!(mem.buffer().size(), mem.size() as u64);
+}
+
+#[test]
+fn upload_with_encoder_works() {
+    let wgpu = get_wgpu();
+
+    let memories = [
+        SimpleGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device),
+        SimpleGpuMemory::without_mirror(wgpu::BufferUsages::empty(), &wgpu.device),
+    ];
+
+    for mut mem in memories {
+        let mut belt = wgpu::util::StagingBelt::new(256);
+        let mut allocations = Vec::new();
+
+        for frame in 0..8 {
+            // Free some memory and allocate more, so the buffer has to be
+            // compacted and grown every frame
+            if allocations.len() > 2 {
+                let (index, _) = allocations.remove(frame % allocations.len());
+                mem.free(index);
+            }
+
+            for i in 0..3 {
+                let param = (frame * 3 + i) as u32;
+                let index = mem.allocate(frame + i + 1);
+                mem.get(&index).fill(Entity { param });
+                allocations.push((index, param));
+            }
+
+            let mut encoder = wgpu.device.create_command_encoder(&Default::default());
+            mem.upload_with_encoder(&wgpu.queue, &wgpu.device, &mut encoder, &mut belt);
+            belt.finish();
+            wgpu.queue.submit([encoder.finish()]);
+            belt.recall();
+
+            assert!(!mem.mutated());
+            assert_eq!(mem.fragmentation(), 0.0);
+
+            let gpu_data = read_buffer(&wgpu, mem.buffer(), mem.size() as u64);
+            let gpu_entities: &[Entity] = bytemuck::cast_slice(&gpu_data);
+
+            for (index, param) in &allocations {
+                let offset = mem.offset(index) as usize / size_of::<Entity>();
+                let len = mem.len_of(index);
+
+                assert!(gpu_entities[offset..(offset + len)]
+                    .iter()
+                    .all(|entity| entity.param == *param));
+            }
+        }
+    }
+}
+
+#[test]
+fn write_buffer_with_works() {
+    let wgpu = get_wgpu();
+
+    let memories = [
+        SimpleGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device),
+        SimpleGpuMemory::without_mirror(wgpu::BufferUsages::empty(), &wgpu.device),
+    ];
+
+    for mut mem in memories {
+        let first = mem.allocate(4);
+        mem.write_buffer_with(&first, &wgpu.queue, &wgpu.device, |entities| {
+            entities.fill(Entity { param: 1 })
+        });
+
+        // The buffer has to grow for this one
+        let second = mem.allocate(64);
+        mem.write_buffer_with(&second, &wgpu.queue, &wgpu.device, |entities| {
+            for (i, entity) in entities.iter_mut().enumerate() {
+                entity.param = i as u32;
+            }
+        });
+
+        let gpu_data = read_buffer(&wgpu, mem.buffer(), mem.size() as u64);
+        let gpu_entities: &[Entity] = bytemuck::cast_slice(&gpu_data);
+
+        let offset = mem.offset(&first) as usize / size_of::<Entity>();
+        assert!(gpu_entities[offset..(offset + 4)]
+            .iter()
+            .all(|entity| entity.param == 1));
+
+        let offset = mem.offset(&second) as usize / size_of::<Entity>();
+        for i in 0..64 {
+            assert_eq!(gpu_entities[offset + i].param, i as u32);
+        }
+
+        if mem.has_mirror() {
+            assert_eq!(mem.get(&second)[63].param, 63);
+        }
+    }
+}
+
+#[test]
+fn upload_with_budget_works() {
+    let wgpu = get_wgpu();
+
+    let memories = [
+        SimpleGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device),
+        SimpleGpuMemory::without_mirror(wgpu::BufferUsages::empty(), &wgpu.device),
+    ];
+
+    for mut mem in memories {
+        let indices = (0..10)
+            .map(|i| {
+                let index = mem.allocate(4);
+                mem.get(&index).fill(Entity { param: i });
+                mem.set_priority(&index, i % 5);
+                index
+            })
+            .collect::<Vec<_>>();
+
+        // Bigger than the entire budget
+        let big = mem.allocate(64);
+        mem.get(&big).fill(Entity { param: 100 });
+        mem.set_priority(&big, 10);
+
+        assert_eq!(mem.pending().count(), 11);
+
+        let budget = size_of::<Entity>() * 4 * 2;
+
+        // Only the big allocation, as it's over the budget by itself
+        mem.upload_with_budget(&wgpu.queue, &wgpu.device, budget);
+        assert!(mem.mutated());
+        assert!(!mem.is_pending(&big));
+        assert_eq!(mem.pending().count(), 10);
+
+        // Then the ones with priority 4
+        mem.upload_with_budget(&wgpu.queue, &wgpu.device, budget);
+        assert!(!mem.is_pending(&indices[4]));
+        assert!(!mem.is_pending(&indices[9]));
+        assert_eq!(mem.pending().count(), 8);
+
+        while mem.mutated() {
+            mem.upload_with_budget(&wgpu.queue, &wgpu.device, budget);
+        }
+
+        assert_eq!(mem.pending().count(), 0);
+
+        let gpu_data = read_buffer(&wgpu, mem.buffer(), mem.size() as u64);
+        let gpu_entities: &[Entity] = bytemuck::cast_slice(&gpu_data);
+
+        for (i, index) in indices.iter().enumerate() {
+            let offset = mem.offset(index) as usize / size_of::<Entity>();
+
+            assert!(gpu_entities[offset..(offset + 4)]
+                .iter()
+                .all(|entity| entity.param == i as u32));
+        }
+
+        let offset = mem.offset(&big) as usize / size_of::<Entity>();
+        assert!(gpu_entities[offset..(offset + 64)]
+            .iter()
+            .all(|entity| entity.param == 100));
+    }
+}
+
+#[test]
+fn read_back_works() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::new(wgpu::BufferUsages::STORAGE, &wgpu.device);
+
+    let indices = (0..8)
+        .map(|i| {
+            let index = mem.allocate(i + 1);
+            mem.get(&index).fill(Entity { param: i as u32 });
+            index
+        })
+        .collect::<Vec<_>>();
+
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    // Stand-in for a compute shader writing to the buffer
+    let offset = mem.offset(&indices[3]);
+    let written = [Entity { param: 300 }; 4];
+    wgpu.queue
+        .write_buffer(mem.buffer(), offset, bytemuck::cast_slice(&written));
+
+    let everything = mem.read_back(&wgpu.queue, &wgpu.device);
+    let some = mem.read_back_allocations(&indices[2..4], &wgpu.queue, &wgpu.device);
+    wgpu.device.poll(wgpu::Maintain::Wait);
+
+    let everything = pollster::block_on(everything).unwrap();
+    let some = pollster::block_on(some).unwrap();
+
+    assert_eq!(everything.iter().count(), 8);
+    assert_eq!(some.iter().count(), 2);
+    assert!(some.get(&indices[0]).is_none());
+
+    for readback in [&everything, &some] {
+        assert!(readback
+            .get(&indices[2])
+            .unwrap()
+            .iter()
+            .all(|e| e.param == 2));
+        assert!(readback
+            .get(&indices[3])
+            .unwrap()
+            .iter()
+            .all(|e| e.param == 300));
+    }
+
+    assert_eq!(mem.get(&indices[3])[0].param, 3);
+
+    mem.write_back(&some);
+    assert!(mem.get(&indices[3]).iter().all(|e| e.param == 300));
+    assert_eq!(mem.get(&indices[4])[0].param, 4);
+}
+
+#[cfg(feature = "verify")]
+#[test]
+fn verify_finds_mismatches() {
+    use wgpu_memory::verify::VerifyError;
+
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device);
+    mem.set_verify_every(1);
+
+    let indices = (0..8)
+        .map(|i| {
+            let index = mem.allocate(4);
+            mem.get(&index).fill(Entity { param: i });
+            index
+        })
+        .collect::<Vec<_>>();
+
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    mem.free(indices[0]);
+    mem.optimize(Strategy::SortSizeAscending, &wgpu.queue, &wgpu.device);
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    // Change the second item of an allocation behind the mirror's back
+    let offset = mem.offset(&indices[5]) + size_of::<Entity>() as u64;
+    wgpu.queue.write_buffer(
+        mem.buffer(),
+        offset,
+        bytemuck::bytes_of(&Entity { param: 500 }),
+    );
+
+    match mem.verify(&wgpu.queue, &wgpu.device) {
+        Err(VerifyError::Mismatch { index, range }) => {
+            assert_eq!(index, indices[5]);
+            assert_eq!(range.start as u64, offset);
+            assert!(range.len() <= size_of::<Entity>());
+        }
+        result => panic!("Expected a mismatch, got {result:?}"),
+    }
+
+    // Changed on the CPU since the last upload, so this isn't a mismatch
+    mem.get(&indices[5])[1] = Entity { param: 600 };
+    assert!(mem.verify(&wgpu.queue, &wgpu.device).is_ok());
+}
+
+#[test]
+fn snapshot_restore_works() {
+    use wgpu_memory::snapshot::SnapshotError;
+
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let indices = (0..8)
+        .map(|i| {
+            let index = mem.allocate(i + 1);
+            mem.get(&index).fill(Entity { param: i as u32 });
+            index
+        })
+        .collect::<Vec<_>>();
+
+    mem.free(indices[2]);
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    let snapshot = mem.snapshot();
+    let size = mem.size();
+
+    // Change everything after taking the snapshot
+    mem.free(indices[0]);
+    mem.get(&indices[1]).fill(Entity { param: 100 });
+    let new_index = mem.allocate(16);
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    mem.restore(&snapshot).unwrap();
+    assert!(mem.mutated());
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    assert_eq!(mem.size(), size);
+    assert_eq!(mem.len_of(&indices[0]), 1);
+    assert!(
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| mem.len_of(&new_index))).is_err()
+    );
+
+    let gpu_data = read_buffer(&wgpu, mem.buffer(), mem.size() as u64);
+    let gpu_entities: &[Entity] = bytemuck::cast_slice(&gpu_data);
+
+    for (i, index) in indices.iter().enumerate().filter(|(i, _)| *i != 2) {
+        let offset = mem.offset(index) as usize / size_of::<Entity>();
+
+        assert!(mem.get(index).iter().all(|entity| entity.param == i as u32));
+        assert!(gpu_entities[offset..(offset + i + 1)]
+            .iter()
+            .all(|entity| entity.param == i as u32));
+    }
+
+    let mut other = SimpleGpuMemory::<[u32; 2]>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+    assert!(matches!(
+        other.restore(&snapshot),
+        Err(SnapshotError::ItemSizeMismatch { .. })
+    ));
+}
+
+#[test]
+fn restore_keeps_new_indices_invalid() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let kept = mem.allocate_labeled(1, "kept");
+    let freed = mem.allocate(1);
+    mem.free(freed);
+
+    let snapshot = mem.snapshot();
+
+    let new_indices = (0..4).map(|_| mem.allocate(1)).collect::<Vec<_>>();
+    let new_group = mem.create_group();
+    mem.free(kept);
+
+    mem.restore(&snapshot).unwrap();
+    assert!(mem.contains(&kept));
+    assert_eq!(mem.label_of(&kept).as_deref(), Some("kept"));
+
+    mem.free(kept);
+    let indices = (0..8).map(|_| mem.allocate(1)).collect::<Vec<_>>();
+    let group = mem.create_group();
+
+    assert!(!mem.contains(&kept));
+    assert!(new_indices.iter().all(|index| !mem.contains(index)));
+    assert!(indices.iter().all(|index| mem.contains(index)));
+    assert_ne!(group, new_group);
+    assert_eq!(mem.group_len(new_group), 0);
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn snapshot_bytes_work() {
+    use wgpu_memory::snapshot::{Snapshot, SnapshotError};
+
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let index = mem.allocate(4);
+    mem.get(&index).fill(Entity { param: 4 });
+
+    let bytes = mem.snapshot().to_bytes();
+
+    mem.free(index);
+    mem.restore(&Snapshot::from_bytes(&bytes).unwrap()).unwrap();
+    assert!(mem.get(&index).iter().all(|entity| entity.param == 4));
+
+    assert_eq!(
+        Snapshot::from_bytes(&bytes[..bytes.len() / 2]).unwrap_err(),
+        SnapshotError::Corrupted
+    );
+    assert_eq!(
+        Snapshot::from_bytes(b"nope").unwrap_err(),
+        SnapshotError::NotASnapshot
+    );
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn corrupted_snapshots_are_rejected() {
+    use wgpu_memory::snapshot::{Snapshot, SnapshotError};
+
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let index = mem.allocate(4);
+    mem.get(&index).fill(Entity { param: 4 });
+
+    let bytes = mem.snapshot().to_bytes();
+
+    // The header, `item_size`, `mirrored` and `data` come before `end`
+    let end_offset = 8 + 8 + 1 + 8 + mem.size();
+    let with_end = |end: u64| {
+        let mut bytes = bytes.clone();
+        bytes[end_offset..(end_offset + 8)].copy_from_slice(&end.to_le_bytes());
+
+        Snapshot::from_bytes(&bytes).unwrap()
+    };
+
+    // `end` is past the memory in the snapshot
+    assert_eq!(
+        mem.restore(&with_end(mem.size() as u64 + 4)),
+        Err(SnapshotError::Corrupted)
+    );
+
+    // The allocation is past `end`
+    assert_eq!(mem.restore(&with_end(4)), Err(SnapshotError::Corrupted));
+
+    // Nothing was restored
+    assert!(mem.get(&index).iter().all(|entity| entity.param == 4));
+    assert_eq!(mem.restore(&with_end(mem.size() as u64)), Ok(()));
+}
+
+#[test]
+fn stats_work() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let a = mem.allocate(10);
+    let _b = mem.allocate(20);
+    let c = mem.allocate(30);
+
+    let stats = mem.stats();
+    assert_eq!(stats.allocations, 3);
+    assert_eq!(stats.free_ranges, 0);
+    assert_eq!(stats.peak_size, size_of::<Entity>() * 60);
+    assert_eq!(stats.uploaded_bytes, 0);
+
+    mem.free(a);
+    mem.free(c);
+
+    let stats = mem.stats();
+    assert_eq!(stats.allocations, 1);
+    assert_eq!(stats.free_ranges, 2);
+    assert_eq!(stats.free_bytes, size_of::<Entity>() * 40);
+    assert_eq!(stats.largest_free_block, size_of::<Entity>() * 30);
+    assert_eq!(stats.fragmentation, 0.25);
+    assert_eq!(stats.peak_size, size_of::<Entity>() * 60);
+
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    let stats = mem.stats();
+    assert_eq!(stats.free_ranges, 0);
+    assert_eq!(stats.fragmentation, 0.0);
+    assert_eq!(stats.uploaded_bytes, size_of::<Entity>() as u64 * 20);
+    assert_eq!(stats.gpu_capacity, size_of::<Entity>() as u64 * 20);
+    assert!(stats.cpu_capacity >= size_of::<Entity>() * 20);
+}
+
+#[test]
+fn memory_map_works() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+    mem.set_compact_on_upload(false);
+
+    let group = mem.create_group();
+    let a = mem.allocate_in(group, 1);
+    let b = mem.allocate(2);
+    let c = mem.allocate(1);
+    mem.free(b);
+
+    let map = mem.memory_map();
+    assert_eq!(map.size, size_of::<Entity>() * 4);
+    assert_eq!(map.holes.len(), 1);
+    assert_eq!(map.holes[0], size_of::<Entity>()..size_of::<Entity>() * 3);
+
+    let ages = map
+        .allocations
+        .iter()
+        .map(|allocation| (allocation.index, allocation.group, allocation.age))
+        .collect::<Vec<_>>();
+    assert_eq!(ages, [(a, Some(group), 2), (c, None, 0)]);
+
+    let ppm = map.to_ppm(4, 1, Coloring::Group);
+    let header = b"P6\n4 1\n255\n";
+    assert_eq!(&ppm[..header.len()], header);
+
+    let pixels = ppm[header.len()..].chunks(3).collect::<Vec<_>>();
+    assert_eq!(pixels.len(), 4);
+    assert_eq!(pixels[1], pixels[2]);
+    assert_ne!(pixels[0], pixels[1]);
+    assert_ne!(pixels[0], pixels[3]);
+
+    let svg = map.to_svg(400, 20, Coloring::Age);
+    assert!(svg.starts_with("<svg"));
+    assert_eq!(svg.matches("<rect").count(), 3);
+    assert!(svg.contains("Hole: bytes 4..12"));
+}
+
+#[test]
+fn labels_work() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let mut a = mem.allocate_labeled(1, "particles");
+    let b = mem.allocate_labeled(2, "particles");
+    let c = mem.allocate_labeled(3, format!("mesh {}", 7));
+    let d = mem.allocate(4);
+
+    assert_eq!(mem.label_of(&a).as_deref(), Some("particles"));
+    assert_eq!(mem.label_of(&c).as_deref(), Some("mesh 7"));
+    assert_eq!(mem.label_of(&d), None);
+
+    // Resizing keeps the label, even when the allocation has to move
+    mem.resize(&mut a, 10);
+    assert_eq!(mem.label_of(&a).as_deref(), Some("particles"));
+
+    mem.free(b);
+
+    let stats = mem.stats();
+    assert_eq!(stats.labels.len(), 2);
+    assert_eq!(stats.labels["particles"].allocations, 1);
+    assert_eq!(stats.labels["particles"].size, size_of::<Entity>() * 10);
+    assert_eq!(stats.labels["mesh 7"].size, size_of::<Entity>() * 3);
+
+    let map = mem.memory_map();
+    let labeled = map
+        .allocations
+        .iter()
+        .filter(|allocation| allocation.label.is_some())
+        .count();
+    assert_eq!(labeled, 2);
+    assert!(map.to_svg(100, 10, Coloring::Label).contains("mesh 7"));
+
+    mem.free(a);
+    mem.free(c);
+    mem.free(d);
+    assert!(mem.stats().labels.is_empty());
+}
+
+#[test]
+fn leak_report_works() {
+    let wgpu = get_wgpu();
+
+    let report = Arc::new(Mutex::new(None));
+
+    {
+        let mut mem = SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+        let reported = Arc::clone(&report);
+        mem.set_on_leak(OnLeak::Report(Box::new(move |leaks: &LeakReport| {
+            *reported.lock().unwrap() = Some(leaks.clone());
+        })));
+
+        let a = mem.allocate_labeled(2, "forgotten");
+        let b = mem.allocate(1);
+        let c = mem.allocate(5);
+        mem.free(b);
+
+        let leaks = mem.leaks();
+        assert_eq!(leaks.leaks.len(), 2);
+        assert_eq!(leaks.leaks[0].index, c);
+        assert_eq!(leaks.leaks[1].index, a);
+
+        mem.free(c);
+    }
+
+    let report = report.lock().unwrap().take().unwrap();
+    assert_eq!(report.leaks.len(), 1);
+    assert_eq!(report.size(), size_of::<Entity>() * 2);
+    assert_eq!(report.leaks[0].label.as_deref(), Some("forgotten"));
+    assert!(report.to_string().contains("\"forgotten\""));
+
+    #[cfg(feature = "backtraces")]
+    assert!(report.leaks[0].backtrace.is_some());
+
+    // Nothing is reported without leaks
+    let mut mem = SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+    mem.set_on_leak(OnLeak::Panic);
+
+    let index = mem.allocate(1);
+    mem.free(index);
+}
+
+#[test]
+#[should_panic(expected = "1 allocations leaked")]
+fn leak_panics() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+    mem.set_on_leak(OnLeak::Panic);
+
+    mem.allocate(1);
+}
+
+#[test]
+fn contains_works() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let a = mem.allocate(2);
+    let mut b = mem.allocate(1);
+    assert!(mem.contains(&a));
+    assert!(mem.contains(&b));
+
+    let old_b = b;
+    mem.resize(&mut b, 10);
+    assert!(mem.contains(&b));
+    assert!(!mem.contains(&old_b));
+
+    mem.free(a);
+    assert!(!mem.contains(&a));
+
+    // The slot of `a` gets reused, but `a` stays freed
+    let c = mem.allocate(2);
+    assert!(mem.contains(&c));
+    assert!(!mem.contains(&a));
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "was used after it was freed at")]
+fn use_after_free_panics() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let index = mem.allocate(1);
+    mem.free(index);
+
+    mem.get(&index);
+}
+
+#[test]
+#[should_panic(expected = "was used after it was freed")]
+fn use_after_free_of_reused_slot_panics() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let a = mem.allocate(1);
+    mem.free(a);
+    let b = mem.allocate(1);
+    mem.free(b);
+
+    mem.get(&a);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "was never allocated in this SimpleGpuMemory")]
+fn never_allocated_panics() {
+    let wgpu = get_wgpu();
+
+    let mem = SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+    let mut other = SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let index = other.allocate(1);
+    other.free(index);
+
+    mem.len_of(&index);
+}
+
+#[test]
+fn poison_works() {
+    const POISON: u32 = 0xDEADBEEF;
+
+    let wgpu = get_wgpu();
+
+    let memories = [
+        SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device),
+        SimpleGpuMemory::<Entity>::without_mirror(wgpu::BufferUsages::empty(), &wgpu.device),
+    ];
+
+    let gpu_entities = |mem: &SimpleGpuMemory<Entity>| -> Vec<Entity> {
+        bytemuck::cast_slice(&read_buffer(&wgpu, mem.buffer(), mem.buffer().size())).to_vec()
+    };
+
+    for mut mem in memories {
+        mem.set_poison(Some(POISON));
+        mem.set_compact_on_upload(false);
+
+        let a = mem.allocate(4);
+        let b = mem.allocate(2);
+        let mut c = mem.allocate(3);
+        mem.get(&a).fill(Entity { param: 1 });
+        mem.get(&b).fill(Entity { param: 2 });
+        mem.get(&c).fill(Entity { param: 3 });
+        mem.upload(&wgpu.queue, &wgpu.device);
+
+        let a_offset = mem.offset(&a) as usize / size_of::<Entity>();
+        let c_offset = mem.offset(&c) as usize / size_of::<Entity>();
+        mem.free(a);
+        // Shrinking frees the front of the allocation
+        mem.resize(&mut c, 1);
+        mem.upload(&wgpu.queue, &wgpu.device);
+
+        let entities = gpu_entities(&mem);
+        let b_offset = mem.offset(&b) as usize / size_of::<Entity>();
+
+        assert!(entities[a_offset..(a_offset + 4)]
+            .iter()
+            .all(|entity| entity.param == POISON));
+        assert!(entities[c_offset..(c_offset + 2)]
+            .iter()
+            .all(|entity| entity.param == POISON));
+        assert_eq!(entities[c_offset + 2].param, 3);
+        assert!(entities[b_offset..(b_offset + 2)]
+            .iter()
+            .all(|entity| entity.param == 2));
+
+        // Allocations made in freed memory start out poisoned
+        let d = mem.allocate(4);
+
+        if mem.has_mirror() {
+            assert!(mem.get(&d).iter().all(|entity| entity.param == POISON));
+        }
+
+        mem.free(d);
+
+        // Compacting leaves the end of the buffer free
+        mem.set_compact_on_upload(true);
+        mem.upload(&wgpu.queue, &wgpu.device);
+
+        let entities = gpu_entities(&mem);
+        let end = mem.len();
+
+        assert_eq!(
+            entities[mem.offset(&b) as usize / size_of::<Entity>()].param,
+            2
+        );
+        assert_eq!(
+            entities[mem.offset(&c) as usize / size_of::<Entity>()].param,
+            3
+        );
+        assert!(entities[end..].iter().all(|entity| entity.param == POISON));
+    }
+}
+
+#[test]
+fn poison_works_with_budget() {
+    const POISON: u32 = 0xDEADBEEF;
+
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+    mem.set_poison(Some(POISON));
+    mem.set_compact_on_upload(false);
+
+    let indices = [1, 7, 0].map(|param| {
+        let index = mem.allocate(1);
+        mem.get(&index)[0] = Entity { param };
+        index
+    });
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    mem.free(indices[1]);
+    mem.upload_with_budget(&wgpu.queue, &wgpu.device, 0);
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    let gpu_data = read_buffer(&wgpu, mem.buffer(), mem.buffer().size());
+    let params = bytemuck::cast_slice::<u8, Entity>(&gpu_data)
+        .iter()
+        .map(|entity| entity.param)
+        .collect::<Vec<_>>();
+
+    assert_eq!(params, [1, POISON, 0]);
+}
+
+#[test]
+fn poison_works_with_encoder() {
+    const POISON: u32 = 0xDEADBEEF;
+
+    let wgpu = get_wgpu();
+
+    for (mirrored, with_belt) in [(true, false), (true, true), (false, false), (false, true)] {
+        let mut mem = match mirrored {
+            true => SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device),
+            false => SimpleGpuMemory::without_mirror(wgpu::BufferUsages::empty(), &wgpu.device),
+        };
+        mem.set_poison(Some(POISON));
+
+        let a = mem.allocate(4);
+        let b = mem.allocate(2);
+        mem.get(&a).fill(Entity { param: 1 });
+        mem.get(&b).fill(Entity { param: 2 });
+        mem.upload(&wgpu.queue, &wgpu.device);
+
+        // Compacting moves `b` to the front, leaving the end free
+        mem.free(a);
+
+        let mut belt = wgpu::util::StagingBelt::new(256);
+        let mut encoder = wgpu.device.create_command_encoder(&Default::default());
+
+        if with_belt {
+            mem.upload_with_encoder(&wgpu.queue, &wgpu.device, &mut encoder, &mut belt);
+        } else {
+            mem.compact_with_encoder(&wgpu.queue, &wgpu.device, &mut encoder);
+        }
+
+        belt.finish();
+        wgpu.queue.submit([encoder.finish()]);
+        assert!(!mem.mutated());
+
+        let gpu_data = read_buffer(&wgpu, mem.buffer(), mem.buffer().size());
+        let entities: &[Entity] = bytemuck::cast_slice(&gpu_data);
+
+        assert_eq!(mem.offset(&b), 0);
+        assert!(entities[..2].iter().all(|entity| entity.param == 2));
+        assert!(entities[2..].iter().all(|entity| entity.param == POISON));
+    }
+}
+
+#[test]
+fn get_ref_works() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let a = mem.allocate(2);
+    let b = mem.allocate(3);
+    mem.get(&a).fill(Entity { param: 1 });
+    mem.get(&b).fill(Entity { param: 2 });
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    assert_eq!(mem.get_ref(&a).len(), 2);
+    assert!(mem.get_ref(&b).iter().all(|entity| entity.param == 2));
+
+    let mut allocations = mem
+        .iter()
+        .map(|(index, memory)| (index, memory.len(), memory[0].param))
+        .collect::<Vec<_>>();
+    allocations.sort_by_key(|(_, len, _)| *len);
+    assert_eq!(allocations, [(a, 2, 1), (b, 3, 2)]);
+
+    // Reading doesn't need another upload
+    assert!(!mem.mutated());
+    assert!(!mem.is_pending(&a));
+}
+
+#[test]
+fn get_many_mut_works() {
+    let wgpu = get_wgpu();
+
+    let memories = [
+        SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device),
+        SimpleGpuMemory::<Entity>::without_mirror(wgpu::BufferUsages::empty(), &wgpu.device),
+    ];
+
+    for mut mem in memories {
+        let a = mem.allocate(3);
+        let b = mem.allocate(3);
+        let c = mem.allocate(1);
+        mem.get(&c)[0] = Entity { param: 7 };
+        mem.upload(&wgpu.queue, &wgpu.device);
+
+        let [a_memory, b_memory] = mem.get_many_mut([&a, &b]).unwrap();
+        a_memory.fill(Entity { param: 1 });
+        b_memory.copy_from_slice(a_memory);
+        b_memory[2].param = 2;
+
+        assert!(mem.mutated());
+        assert!(mem.is_pending(&a) && mem.is_pending(&b) && !mem.is_pending(&c));
+
+        assert_eq!(
+            mem.get_many_mut([&a, &c, &a]).err(),
+            Some(GetManyMutError::DuplicateIndex {
+                first: 0,
+                second: 2
+            })
+        );
+
+        // Empty allocations can be in the same place without being the same
+        let empty = [mem.allocate(0), mem.allocate(0)];
+        assert_eq!(mem.offset(&empty[0]), mem.offset(&empty[1]));
+        assert!(mem.get_many_mut([&empty[0], &empty[1], &a]).is_ok());
+        assert!(mem.get_many_mut([&empty[1], &empty[1]]).is_err());
+        mem.fill(&empty[0], Entity { param: 0 });
+
+        mem.upload(&wgpu.queue, &wgpu.device);
+
+        let gpu_data = read_buffer(&wgpu, mem.buffer(), mem.buffer().size());
+        let gpu_entities: &[Entity] = bytemuck::cast_slice(&gpu_data);
+        let entity = |index, i| gpu_entities[mem.offset(index) as usize / size_of::<Entity>() + i];
+
+        assert_eq!(entity(&a, 2).param, 1);
+        assert_eq!(entity(&b, 0).param, 1);
+        assert_eq!(entity(&b, 2).param, 2);
+        assert_eq!(entity(&c, 0).param, 7);
+    }
+}
+
+#[test]
+fn iter_mut_works() {
+    let wgpu = get_wgpu();
+
+    let memories = [
+        SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device),
+        SimpleGpuMemory::<Entity>::without_mirror(wgpu::BufferUsages::empty(), &wgpu.device),
+    ];
+
+    for mut mem in memories {
+        let indices = (1..5).map(|len| mem.allocate(len)).collect::<Vec<_>>();
+        mem.upload(&wgpu.queue, &wgpu.device);
+
+        for (index, memory) in mem.iter_mut() {
+            assert!(indices.contains(&index));
+
+            for (i, entity) in memory.iter_mut().enumerate() {
+                entity.param = i as u32;
+            }
+        }
+
+        assert!(indices.iter().all(|index| mem.is_pending(index)));
+        mem.upload(&wgpu.queue, &wgpu.device);
+
+        let gpu_data = read_buffer(&wgpu, mem.buffer(), mem.buffer().size());
+        let gpu_entities: &[Entity] = bytemuck::cast_slice(&gpu_data);
+
+        for index in &indices {
+            let offset = mem.offset(index) as usize / size_of::<Entity>();
+
+            for i in 0..mem.len_of(index) {
+                assert_eq!(gpu_entities[offset + i].param, i as u32);
+            }
+        }
+    }
+}
+
+#[test]
+fn write_helpers_only_upload_what_changed() {
+    let wgpu = get_wgpu();
+
+    let memories = [
+        SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device),
+        SimpleGpuMemory::<Entity>::without_mirror(wgpu::BufferUsages::empty(), &wgpu.device),
+    ];
+
+    for mut mem in memories {
+        let a = mem.allocate(8);
+        let b = mem.allocate(2);
+        mem.fill(&a, Entity { param: 1 });
+        mem.fill(&b, Entity { param: 2 });
+        mem.upload(&wgpu.queue, &wgpu.device);
+
+        mem.set(&a, 1, Entity { param: 5 });
+        mem.write(&a, 4, &[Entity { param: 6 }, Entity { param: 7 }]);
+        assert!(mem.is_pending(&a) && !mem.is_pending(&b));
+
+        let uploaded = mem.stats().uploaded_bytes;
+        mem.upload(&wgpu.queue, &wgpu.device);
+
+        // Only the three items that were written
+        assert_eq!(
+            mem.stats().uploaded_bytes - uploaded,
+            size_of::<Entity>() as u64 * 3
+        );
+
+        let gpu_data = read_buffer(&wgpu, mem.buffer(), mem.buffer().size());
+        let gpu_entities: &[Entity] = bytemuck::cast_slice(&gpu_data);
+        let params = |index| {
+            let offset = mem.offset(index) as usize / size_of::<Entity>();
+
+            gpu_entities[offset..(offset + mem.len_of(index))]
+                .iter()
+                .map(|entity| entity.param)
+                .collect::<Vec<_>>()
+        };
+
+        // Without a mirror, the rest of `a` isn't zeroed
+        assert_eq!(params(&a), [1, 5, 1, 1, 6, 7, 1, 1]);
+        assert_eq!(params(&b), [2, 2]);
+    }
+}
+
+#[test]
+fn copy_allocation_works() {
+    let wgpu = get_wgpu();
+
+    let memories = [
+        SimpleGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device),
+        SimpleGpuMemory::<Entity>::without_mirror(wgpu::BufferUsages::empty(), &wgpu.device),
+    ];
+
+    for mut mem in memories {
+        let a = mem.allocate(3);
+        let b = mem.allocate(3);
+        mem.fill(&a, Entity { param: 1 });
+        mem.fill(&b, Entity { param: 2 });
+        mem.upload(&wgpu.queue, &wgpu.device);
+
+        // Written before copying, so it's copied as well
+        mem.set(&a, 2, Entity { param: 3 });
+        mem.copy_allocation(&a, &b);
+        mem.copy_allocation(&a, &a);
+        mem.set(&a, 0, Entity { param: 9 });
+
+        assert!(mem.is_pending(&b));
+        mem.upload(&wgpu.queue, &wgpu.device);
+
+        let gpu_data = read_buffer(&wgpu, mem.buffer(), mem.buffer().size());
+        let gpu_entities: &[Entity] = bytemuck::cast_slice(&gpu_data);
+        let params = |index| {
+            let offset = mem.offset(index) as usize / size_of::<Entity>();
+
+            gpu_entities[offset..(offset + 3)]
+                .iter()
+                .map(|entity| entity.param)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(params(&a), [9, 1, 3]);
+        assert_eq!(params(&b), [1, 1, 3]);
+    }
+}
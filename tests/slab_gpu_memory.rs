@@ -0,0 +1,175 @@
+use std::mem::size_of;
+
+use common::{get_wgpu, Entity};
+use wgpu_memory::{slab::SlabGpuMemory, GpuMemory};
+
+mod common;
+
+#[test]
+fn allocations_work() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SlabGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    for _ in 0..100 {
+        let mut index = mem.allocate(3);
+        assert_eq!(mem.size(), size_of::<Entity>() * 3);
+
+        mem.get(&index).fill(Entity { param: 3 });
+
+        // Moves to a range outside of the slabs
+        mem.resize(&mut index, 100);
+        assert_eq!(mem.size(), size_of::<Entity>() * 100);
+        assert!(mem.get(&index)[..3].iter().all(|entity| entity.param == 3));
+
+        // Moves back into a slab
+        mem.resize(&mut index, 2);
+        assert_eq!(mem.size(), size_of::<Entity>() * 2);
+        assert!(mem.get(&index).iter().all(|entity| entity.param == 3));
+
+        mem.free(index);
+    }
+
+    assert_eq!(mem.size(), 0);
+}
+
+#[test]
+fn slots_are_reused() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SlabGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let indices = (0..100)
+        .map(|i| mem.allocate(i % 64 + 1))
+        .collect::<Vec<_>>();
+    let capacity = mem.capacity();
+
+    for (i, index) in indices.into_iter().enumerate() {
+        mem.free(index);
+
+        let index = mem.allocate(i % 64 + 1);
+        mem.get(&index).fill(Entity { param: i as u32 });
+    }
+
+    assert_eq!(mem.capacity(), capacity);
+}
+
+#[test]
+fn truncate_releases_empty_slabs() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SlabGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let kept = mem.allocate(1);
+    mem.get(&kept)[0] = Entity { param: 1 };
+
+    let indices = (0..1000)
+        .map(|i| mem.allocate(i % 200 + 1))
+        .collect::<Vec<_>>();
+
+    for index in indices {
+        mem.free(index);
+    }
+
+    mem.optimize(Default::default(), &wgpu.queue, &wgpu.device);
+
+    // Only the slab with `kept` is left
+    assert_eq!(mem.capacity(), 32);
+    assert_eq!(mem.offset(&kept), 0);
+    assert_eq!(mem.get(&kept)[0].param, 1);
+}
+
+#[test]
+fn contains_works() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SlabGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let small = mem.allocate(3);
+    let big = mem.allocate(1000);
+    assert!(mem.contains(&small));
+    assert!(mem.contains(&big));
+
+    mem.free(small);
+    mem.free(big);
+    assert!(!mem.contains(&small));
+    assert!(!mem.contains(&big));
+}
+
+#[test]
+fn double_free_is_ignored() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SlabGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let small = mem.allocate(3);
+    let big = mem.allocate(1000);
+    let kept = mem.allocate(500);
+
+    mem.free(small);
+    mem.free(small);
+    mem.free(big);
+    mem.free(big);
+
+    assert_eq!(mem.len(), 500);
+    assert!(mem.contains(&kept));
+
+    // The range that was freed twice is only handed out once
+    let a = mem.allocate(1000);
+    let b = mem.allocate(1000);
+    assert_ne!(mem.offset(&a), mem.offset(&b));
+}
+
+#[test]
+fn freed_addresses_of_reused_slots_are_stale() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SlabGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let old = mem.allocate(3);
+    mem.free(old);
+
+    let new = mem.allocate(3);
+    assert_eq!(mem.offset(&new), 0);
+    assert!(!mem.contains(&old));
+    assert!(mem.contains(&new));
+
+    // Freeing the old address doesn't free the allocation in its slot
+    mem.free(old);
+    assert!(mem.contains(&new));
+    assert_eq!(mem.len(), 3);
+
+    assert!(std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| mem.offset(&old))).is_err());
+}
+
+#[test]
+fn iter_works() {
+    let wgpu = get_wgpu();
+
+    let mut mem = SlabGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let mut indices = [3, 100, 1, 64, 7]
+        .into_iter()
+        .map(|len| {
+            let index = mem.allocate(len);
+            mem.get(&index).fill(Entity { param: len as u32 });
+            index
+        })
+        .collect::<Vec<_>>();
+
+    mem.resize(&mut indices[0], 4);
+    mem.resize(&mut indices[1], 90);
+    mem.free(indices.remove(2));
+
+    let mut allocations = mem.iter().collect::<Vec<_>>();
+    allocations.sort_by_key(|(index, _)| mem.offset(index));
+    indices.sort_by_key(|index| mem.offset(index));
+
+    assert_eq!(allocations.len(), indices.len());
+
+    for ((index, memory), expected) in allocations.into_iter().zip(&indices) {
+        assert_eq!(index, *expected);
+        assert_eq!(memory.len(), mem.len_of(expected));
+        assert_eq!(memory.as_ptr(), mem.get_ref(expected).as_ptr());
+    }
+}
@@ -0,0 +1,129 @@
+use std::mem::size_of;
+
+use common::{get_wgpu, Entity};
+use wgpu_memory::{tlsf::TlsfGpuMemory, GpuMemory};
+
+mod common;
+
+#[test]
+fn allocations_work() {
+    let wgpu = get_wgpu();
+
+    let mut mem = TlsfGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    for _ in 0..100 {
+        let mut index = mem.allocate(3);
+        assert_eq!(mem.size(), size_of::<Entity>() * 3);
+
+        mem.resize(&mut index, 100);
+        assert_eq!(mem.size(), size_of::<Entity>() * 100);
+
+        mem.resize(&mut index, 1);
+        assert_eq!(mem.size(), size_of::<Entity>());
+
+        mem.get(&index)[0] = Entity { param: 1 };
+        mem.free(index);
+    }
+
+    assert_eq!(mem.size(), 0);
+
+    mem.optimize(Default::default(), &wgpu.queue, &wgpu.device);
+    assert_eq!(mem.capacity(), 0);
+}
+
+#[test]
+fn offsets_stay_stable() {
+    let wgpu = get_wgpu();
+
+    let mut mem = TlsfGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let indices = (0..64)
+        .map(|i| {
+            let index = mem.allocate(i % 7 + 1);
+            mem.get(&index).fill(Entity { param: i as u32 });
+            (index, mem.offset(&index))
+        })
+        .collect::<Vec<_>>();
+
+    for (index, _) in indices.iter().step_by(2) {
+        mem.free(*index);
+    }
+
+    mem.upload(&wgpu.queue, &wgpu.device);
+
+    for (i, (index, offset)) in indices.iter().enumerate().skip(1).step_by(2) {
+        assert_eq!(mem.offset(index), *offset);
+        assert!(mem.get(index).iter().all(|entity| entity.param == i as u32));
+    }
+}
+
+#[test]
+fn churn_keeps_data_intact() {
+    let wgpu = get_wgpu();
+
+    let mut mem = TlsfGpuMemory::new(wgpu::BufferUsages::empty(), &wgpu.device);
+    let mut live = Vec::new();
+    let mut seed = 0x2545_f491_u32;
+
+    for i in 0..2000 {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+
+        match seed % 3 {
+            0 if !live.is_empty() => {
+                let (index, _) = live.swap_remove(seed as usize % live.len());
+                mem.free(index);
+            }
+            1 if !live.is_empty() => {
+                let position = seed as usize % live.len();
+                let (index, param): &mut (_, u32) = &mut live[position];
+
+                mem.resize(index, (seed % 40 + 1) as usize);
+                mem.get(index).fill(Entity { param: *param });
+            }
+            _ => {
+                let index = mem.allocate((seed % 40 + 1) as usize);
+                mem.get(&index).fill(Entity { param: i });
+                live.push((index, i));
+            }
+        }
+
+        for (index, param) in &live {
+            assert!(mem.get(index).iter().all(|entity| entity.param == *param));
+        }
+    }
+
+    let total = live
+        .iter()
+        .map(|(index, _)| mem.len_of(index))
+        .sum::<usize>();
+    assert_eq!(mem.len(), total);
+}
+
+#[test]
+fn stats_work() {
+    let wgpu = get_wgpu();
+
+    let mut mem = TlsfGpuMemory::<Entity>::new(wgpu::BufferUsages::empty(), &wgpu.device);
+
+    let a = mem.allocate(16);
+    let _b = mem.allocate(16);
+    mem.free(a);
+
+    let stats = mem.stats();
+    assert_eq!(stats.allocations, 1);
+    assert_eq!(stats.peak_size, size_of::<Entity>() * 32);
+    assert!(stats.free_ranges >= 1);
+    assert!(stats.largest_free_block >= size_of::<Entity>() * 16);
+    assert_eq!(
+        stats.free_bytes,
+        mem.capacity() * size_of::<Entity>() - mem.size()
+    );
+
+    mem.upload(&wgpu.queue, &wgpu.device);
+    assert_eq!(
+        mem.stats().uploaded_bytes,
+        (mem.capacity() * size_of::<Entity>()) as u64
+    );
+}